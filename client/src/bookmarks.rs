@@ -0,0 +1,274 @@
+// bookmarks.rs
+// saved rooms, read from the config's [rooms] and added to with /bookmark. their passwords can
+// live in the config itself or, with password_storage = "encrypted", in a passwords file next
+// to it that's encrypted with $CLIQUE_PASSPHRASE
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{self, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::PathBuf,
+};
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::Rng;
+use serde::Deserialize;
+use toml_edit::{value, DocumentMut, Item, Table};
+
+use crate::config::SavedRoom;
+
+const PASSPHRASE_VAR: &str = "CLIQUE_PASSPHRASE";
+const PASSWORDS_FILE: &str = "passwords";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordStorage {
+    #[default]
+    None, // /bookmark leaves the password out
+    Config,
+    Encrypted,
+}
+
+#[derive(Clone)]
+pub struct Bookmarks {
+    rooms: BTreeMap<String, SavedRoom>,
+    config_path: Option<PathBuf>, // None when there's no home directory to put one in
+    storage: PasswordStorage,
+}
+
+impl Bookmarks {
+    pub fn new(rooms: BTreeMap<String, SavedRoom>, config_path: Option<PathBuf>, storage: PasswordStorage) -> Self {
+        Bookmarks { rooms, config_path, storage }
+    }
+
+    pub fn aliases(&self) -> impl Iterator<Item = &String> {
+        self.rooms.keys()
+    }
+
+    // without the passwords from the encrypted file
+    pub fn rooms(&self) -> impl Iterator<Item = (&String, &SavedRoom)> {
+        self.rooms.iter()
+    }
+
+    pub fn storage(&self) -> PasswordStorage {
+        self.storage
+    }
+
+    // with the password from the encrypted file if it isn't in the config
+    pub fn get(&self, alias: &str) -> Result<Option<SavedRoom>, String> {
+        let Some(mut room) = self.rooms.get(alias).cloned() else {
+            return Ok(None);
+        };
+        if room.password.is_none() && self.storage == PasswordStorage::Encrypted {
+            room.password = self.read_passwords()?.remove(alias);
+        }
+        Ok(Some(room))
+    }
+
+    // replaces any bookmark with the same alias but its log settings. the config file keeps
+    // its comments and layout
+    pub fn save(&mut self, alias: &str, mut room: SavedRoom) -> Result<(), String> {
+        let path = self.config_path.clone().ok_or("no config file to save to, HOME isn't set")?;
+        if let Some(old) = self.rooms.get(alias) {
+            room.log = room.log.or(old.log);
+            room.log_format = room.log_format.or(old.log_format);
+        }
+
+        let password = room.password.take();
+        match (self.storage, password) {
+            (PasswordStorage::Config, password) => room.password = password,
+            (PasswordStorage::Encrypted, password) => {
+                let mut passwords = self.read_passwords()?;
+                match password {
+                    Some(password) => passwords.insert(alias.to_owned(), password),
+                    None => passwords.remove(alias),
+                };
+                self.write_passwords(&passwords)?;
+            }
+            (PasswordStorage::None, _) => {}
+        }
+
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        let mut doc: DocumentMut = text.parse().map_err(|e| format!("{}: {}", path.display(), e))?;
+        let rooms = doc.entry("rooms").or_insert_with(|| {
+            let mut rooms = Table::new();
+            rooms.set_implicit(true); // no bare [rooms] header above the first one
+            Item::Table(rooms)
+        });
+        let rooms = rooms.as_table_mut().ok_or(format!("{}: rooms isn't a table", path.display()))?;
+
+        let mut table = Table::new();
+        for (key, field) in [("chat_id", room.chat_id.map(|id| id.to_string())), ("invite", room.invite.clone()), ("username", room.username.clone()), ("password", room.password.clone())] {
+            if let Some(field) = field {
+                table[key] = value(field);
+            }
+        }
+        if let Some(log) = room.log {
+            table["log"] = value(log);
+        }
+        if let Some(format) = room.log_format {
+            table["log_format"] = value(format.as_str());
+        }
+        rooms.insert(alias, Item::Table(table));
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        write_private(&path, doc.to_string().as_bytes())?;
+        self.rooms.insert(alias.to_owned(), room);
+        Ok(())
+    }
+
+    fn passwords_path(&self) -> Result<PathBuf, String> {
+        self.config_path.as_ref().map(|path| path.with_file_name(PASSWORDS_FILE)).ok_or("no config directory for the passwords file, HOME isn't set".into())
+    }
+
+    // salt, nonce, then the encrypted JSON map of alias to password. no file is no passwords
+    fn read_passwords(&self) -> Result<BTreeMap<String, String>, String> {
+        let path = self.passwords_path()?;
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        if data.len() < SALT_LEN + NONCE_LEN {
+            return Err(format!("{} is damaged", path.display()));
+        }
+        let (salt, rest) = data.split_at(SALT_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let plaintext = cipher(salt)?.decrypt(Nonce::from_slice(nonce), ciphertext).map_err(|_| format!("{} doesn't open with this {}", path.display(), PASSPHRASE_VAR))?;
+        serde_json::from_slice(&plaintext).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // a fresh salt and nonce every time
+    fn write_passwords(&self, passwords: &BTreeMap<String, String>) -> Result<(), String> {
+        let path = self.passwords_path()?;
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::rng().fill(&mut salt);
+        rand::rng().fill(&mut nonce);
+
+        let plaintext = serde_json::to_vec(passwords).map_err(|e| e.to_string())?;
+        let ciphertext = cipher(&salt)?.encrypt(Nonce::from_slice(&nonce), plaintext.as_slice()).map_err(|_| "encrypting the passwords failed".to_owned())?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        write_private(&path, &[&salt[..], &nonce[..], &ciphertext[..]].concat())
+    }
+}
+
+fn cipher(salt: &[u8]) -> Result<ChaCha20Poly1305, String> {
+    let passphrase = env::var(PASSPHRASE_VAR).map_err(|_| format!("set {} to use the encrypted passwords file", PASSPHRASE_VAR))?;
+    let mut key = [0u8; 32];
+    Argon2::default().hash_password_into(passphrase.as_bytes(), salt, &mut key).map_err(|e| e.to_string())?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+// readable by us only, like the server's admin socket. written next to the old file and renamed
+// over it, so a crash halfway leaves the old one rather than half of each
+pub fn write_private(path: &PathBuf, contents: &[u8]) -> Result<(), String> {
+    let name = path.file_name().ok_or_else(|| format!("{} isn't a file", path.display()))?;
+    let tmp = path.with_file_name(format!(".{}.tmp", name.to_string_lossy()));
+    let written = (|| -> io::Result<()> {
+        let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp)?;
+        // mode only applies to a new file, a leftover one keeps whatever it had
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written.map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a fresh directory per test, the config file in it doesn't have to exist
+    fn bookmarks(name: &str) -> Bookmarks {
+        let dir = env::temp_dir().join(format!("clique-bookmarks-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        Bookmarks::new(BTreeMap::new(), Some(dir.join("config.toml")), PasswordStorage::Encrypted)
+    }
+
+    fn mode(path: &PathBuf) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    // everything that needs the passphrase is in here, other tests would race on the variable
+    #[test]
+    fn passwords_round_trip() {
+        let bookmarks = bookmarks("passwords");
+        let path = bookmarks.passwords_path().unwrap();
+        env::set_var(PASSPHRASE_VAR, "correct horse");
+        assert!(bookmarks.read_passwords().unwrap().is_empty(), "no file is no passwords");
+
+        let passwords = BTreeMap::from([("ops".to_owned(), "hunter2".to_owned()), ("standup".to_owned(), "swordfish".to_owned())]);
+        bookmarks.write_passwords(&passwords).unwrap();
+        assert_eq!(bookmarks.read_passwords().unwrap(), passwords);
+        assert_eq!(mode(&path), 0o600);
+
+        // salt | nonce | ciphertext with its 16 byte tag, both picked again on every write
+        let first = fs::read(&path).unwrap();
+        assert_eq!(first.len(), SALT_LEN + NONCE_LEN + serde_json::to_vec(&passwords).unwrap().len() + 16);
+        assert!(!first.windows(7).any(|window| window == b"hunter2"));
+        bookmarks.write_passwords(&passwords).unwrap();
+        let second = fs::read(&path).unwrap();
+        assert_ne!(first[..SALT_LEN], second[..SALT_LEN]);
+        assert_ne!(first[SALT_LEN..SALT_LEN + NONCE_LEN], second[SALT_LEN..SALT_LEN + NONCE_LEN]);
+
+        env::set_var(PASSPHRASE_VAR, "wrong horse");
+        assert!(bookmarks.read_passwords().unwrap_err().contains("doesn't open with this"));
+        env::set_var(PASSPHRASE_VAR, "correct horse");
+
+        for damaged in [second[..SALT_LEN + NONCE_LEN - 1].to_vec(), second[..second.len() - 1].to_vec(), [&second[..], b"x"].concat()] {
+            fs::write(&path, damaged).unwrap();
+            assert!(bookmarks.read_passwords().is_err());
+        }
+        let mut flipped = second.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        fs::write(&path, flipped).unwrap();
+        assert!(bookmarks.read_passwords().is_err());
+        fs::write(&path, &second[..3]).unwrap();
+        assert!(bookmarks.read_passwords().unwrap_err().ends_with("is damaged"));
+
+        fs::write(&path, &second).unwrap();
+        env::remove_var(PASSPHRASE_VAR);
+        assert!(bookmarks.read_passwords().unwrap_err().contains(PASSPHRASE_VAR));
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn writes_replace_the_file_privately() {
+        let bookmarks = bookmarks("private");
+        let path = bookmarks.config_path.clone().unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        // a temp file left over from a crash, with permissions that are too loose
+        let tmp = path.with_file_name(".config.toml.tmp");
+        fs::write(&tmp, "leftover").unwrap();
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(mode(&path), 0o600);
+        assert!(!tmp.exists());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -1,80 +1,227 @@
 // client.rs
 
+use chrono::{DateTime, Local};
+use cliqueclient::{Client, ClientError, Event, Events, Search};
 use colored::Colorize;
-use protocol::{read_message, write_message, CreateChatRequest, JoinChatRequest, LeaveChatRequest, Packet, ProtocolMessage, SendMessageRequest};
-use std::{error::Error, str::FromStr, sync::Arc};
+use protocol::{ChatMessage, InviteCode, InviteInfo, JoinChatResponse, ReactionCount, Role, RoomRef, SendMessageResponse, MAX_ROOM_TTL, TYPING_TIMEOUT};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    mem,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tokio::{
-    io::{split, AsyncBufReadExt},
-    net::TcpStream,
-    sync::{mpsc, Mutex},
+    sync::Mutex,
+    time::{self, Instant},
 };
 
 use uuid::Uuid;
 
+use crate::{
+    bookmarks::{Bookmarks, PasswordStorage},
+    config::{SavedRoom, Settings},
+    editor::Completer,
+    input::{Input, InputReader},
+    member_keys::MemberKeys,
+    output,
+    transcript::Transcript,
+    ui::{self, Status},
+};
+
+// all output goes through the ui, which prints it or puts it in the message pane
+macro_rules! ui_println {
+    ($($arg:tt)*) => {
+        ui::print_line(format!($($arg)*));
+    };
+}
+
 macro_rules! y_println {
     ($($arg:tt)*) => {
-        println!("{}", format!($($arg)*).yellow());
+        ui::print_line(format!($($arg)*).yellow().to_string());
     };
 }
 
-macro_rules! debug_r_eprintln {
+macro_rules! r_eprintln {
     ($($arg:tt)*) => {
-        if cfg!(debug_assertions) {
-            eprintln!("{}", format!($($arg)*).red());
-        }
+        ui::print_error(format!($($arg)*).red().to_string());
     };
 }
 
 macro_rules! debug_println {
     ($($arg:tt)*) => {
         if cfg!(debug_assertions) {
-            println!("{}", format!($($arg)*).blue());
+            ui::print_line(format!($($arg)*).blue().to_string());
         }
     };
 }
 
 const HELP_TEXT: &str = r#"
 Commands:
-/create [password]           — create a new chat (optional arg password)
-/join <chat_id> <user> [pw]  — join existing chat
+/create [password] [--ttl m] — create a new chat (optional password, --ttl deletes it after m minutes)
+/join <chat_id> [user [pw]]  — join existing chat, user defaults to the username in the config
+/join <invite> [user]        — join with an invite code instead of the chat id and password
+/join @<alias> [user]        — join a room saved in the config
+/bookmark <alias>            — save the current room in the config, to /join @<alias> later
+/bookmarks                   — list saved rooms
+/log on|off                  — start or stop logging this room to a file
 /send <message>              — send to current chat
+/reply <id> <message>        — reply to a message, starting or continuing its thread
+/thread <id>                 — show a message and all replies to it
+/edit <id|last> <message>    — edit one of your messages (last = the one you sent most recently)
+/delete <id|last>            — delete a message, moderators can delete anyone's
+/react <id|last> <emoji>     — react to a message
+/unreact <id|last> <emoji>   — take your reaction back
+/mod <user>, /unmod <user>   — make someone a moderator or take it away (room owner only)
+/password <new>|--remove [--kick]
+                             — change or remove the room password (owner only), --kick removes
+                               everyone who joined with the old one
+/topic [text|--clear]        — show the room topic, or set or clear it (owner and moderators)
+/pin <id|last>, /unpin <id|last>
+                             — pin a message to the room or unpin it (owner and moderators)
+/pins                        — show pinned messages
+/invite [--expires <age>] [--uses <n>]
+                             — create an invite code for this chat (owner and moderators)
+/invites, /revoke <id>       — list open invites, or revoke one
+/search [text] [--from <user>] [--since <age>] [--until <age>]
+                             — search this chat's history, ages look like 30m, 2h, 7d
+/search --more               — next page of the last search
+/more                        — load older messages
+/seen [id|last]              — who has read a message (defaults to your last one)
+/rooms                       — rooms you've joined this session, with unread counts
 /leave                       — leave current chat
 /exit                        — exit
 "#;
 
+// with --output json a line starting with '{' is read as one of these, e.g.
+// {"command": "send", "args": "hello"} or {"command": "join", "args": {"chat_id": "...", "username": "alice"}}
+#[derive(Deserialize)]
+#[serde(tag = "command", content = "args", rename_all = "snake_case")]
 pub enum Command {
-    Create(Option<String>),
-    Join { chat_id: Uuid, username: String, password: Option<String> },
+    Create { password: Option<String>, ttl_minutes: Option<u64> },
+    Join {
+        #[serde(default)] // not needed with an invite
+        chat_id: Uuid,
+        username: Option<String>, // None for the one in the config
+        password: Option<String>,
+        invite: Option<String>,
+    },
+    JoinSaved { alias: String, username: Option<String> },
+    Bookmark(String),
+    Bookmarks,
     Send(String),
+    Reply { message_id: u64, message: String },
+    Thread(u64),
+    Edit { message_id: Option<u64>, message: String }, // None means your last message
+    Delete(Option<u64>),
+    React { message_id: Option<u64>, emoji: String, add: bool },
+    SetRole { username: String, role: Role },
+    SetPassword { password: Option<String>, kick_old: bool },
+    ShowTopic,
+    SetTopic(Option<String>), // None clears it
+    Pins,
+    Pin { message_id: Option<u64>, pinned: bool },
+    Invite { expires_in_secs: Option<u64>, max_uses: Option<u32> },
+    Invites,
+    Revoke(u64),
+    Search(SearchFilter),
+    SearchMore,
+    More,
+    Seen(Option<u64>),
+    Rooms,
+    Log(bool),
     Leave,
     Exit,
     Help,
+    #[serde(skip)]
     Invalid,
 }
 
+impl Command {
+    // every command word, for tab completion
+    pub const NAMES: &[&str] = &[
+        "/create", "/join", "/bookmark", "/bookmarks", "/send", "/reply", "/thread", "/edit", "/delete", "/react", "/unreact", "/mod", "/unmod", "/password", "/topic", "/pins", "/pin", "/unpin",
+        "/invite", "/invites", "/revoke", "/search", "/more", "/seen", "/rooms", "/log", "/leave", "/exit", "/help",
+    ];
+
+    // typing /send every time is annoying. if command doesnt start with '/' implicit send mode.
+    // with --output json a line starting with '{' is a JSON command
+    pub fn from_line(line: &str) -> Result<Command, serde_json::Error> {
+        if output::is_json() && line.starts_with('{') {
+            serde_json::from_str(line)
+        } else if line.starts_with('/') {
+            Ok(line.parse().unwrap_or(Command::Invalid))
+        } else {
+            Ok(Command::Send(line.to_owned()))
+        }
+    }
+
+    // lines kept out of the history file, whichever way the command was written. one that
+    // can't be read might still have a password in it
+    pub fn is_secret_line(line: &str) -> bool {
+        match Command::from_line(line) {
+            Ok(Command::Invalid) | Err(_) => true,
+            Ok(cmd) => cmd.has_secret(),
+        }
+    }
+
+    // commands carrying a password or an invite code
+    pub fn has_secret(&self) -> bool {
+        matches!(
+            self,
+            Command::Create { password: Some(_), .. }
+                | Command::Join { password: Some(_), .. }
+                | Command::Join { invite: Some(_), .. }
+                | Command::SetPassword { password: Some(_), .. }
+        )
+    }
+}
+
 impl FromStr for Command {
     type Err = ();
 
     fn from_str(line: &str) -> Result<Self, Self::Err> {
-        let mut iter = line.trim().split_whitespace();
+        let mut iter = line.split_whitespace();
         match iter.next() {
             Some("/create") => {
-                let pw = iter.next().map(str::to_owned);
-                Ok(Command::Create(pw))
+                let (mut password, mut ttl_minutes) = (None, None);
+                while let Some(arg) = iter.next() {
+                    if arg == "--ttl" {
+                        ttl_minutes = Some(iter.next().and_then(|m| m.parse().ok()).filter(|m: &u64| m.checked_mul(60).is_some_and(|secs| secs <= MAX_ROOM_TTL.as_secs())).ok_or(())?);
+                    } else if password.is_none() {
+                        password = Some(arg.to_owned());
+                    } else {
+                        return Err(());
+                    }
+                }
+                Ok(Command::Create { password, ttl_minutes })
             }
             Some("/join") => {
-                if let (Some(chat_id), Some(username)) = (iter.next(), iter.next()) {
-                    match Uuid::parse_str(chat_id) {
-                        Ok(chat_id) => {
-                            let password = iter.next().map(str::to_owned);
-                            Ok(Command::Join { chat_id, username: username.into(), password })
-                        }
-                        Err(_) => Err(()),
+                let target = iter.next().ok_or(())?;
+                let username = iter.next().map(str::to_owned);
+                // an invite code says which chat it's for, no password needed with one
+                if let Some(alias) = target.strip_prefix('@').filter(|alias| !alias.is_empty()) {
+                    match iter.next() {
+                        None => Ok(Command::JoinSaved { alias: alias.into(), username }),
+                        Some(_) => Err(()),
                     }
+                } else if let Ok(chat_id) = Uuid::parse_str(target) {
+                    let password = iter.next().map(str::to_owned);
+                    Ok(Command::Join { chat_id, username, password, invite: None })
+                } else if let (Some(invite), None) = (InviteCode::decode(target), iter.next()) {
+                    Ok(Command::Join { chat_id: invite.chat_id, username, password: None, invite: Some(target.into()) })
                 } else {
                     Err(())
                 }
             }
+            Some("/bookmark") => match (iter.next().map(|alias| alias.trim_start_matches('@')), iter.next()) {
+                (Some(alias), None) if !alias.is_empty() => Ok(Command::Bookmark(alias.into())),
+                _ => Err(()),
+            },
+            Some("/bookmarks") => Ok(Command::Bookmarks),
             Some("/send") => {
                 let msg = iter.collect::<Vec<_>>().join(" ");
                 if msg.is_empty() {
@@ -83,6 +230,114 @@ impl FromStr for Command {
                     Ok(Command::Send(msg))
                 }
             }
+            Some("/reply") => {
+                let message_id = parse_message_ref(iter.next().ok_or(())?)?.ok_or(())?;
+                let msg = iter.collect::<Vec<_>>().join(" ");
+                if msg.is_empty() {
+                    Err(())
+                } else {
+                    Ok(Command::Reply { message_id, message: msg })
+                }
+            }
+            Some("/thread") => match (iter.next(), iter.next()) {
+                (Some(id), None) => Ok(Command::Thread(parse_message_ref(id)?.ok_or(())?)),
+                _ => Err(()),
+            },
+            Some("/edit") => {
+                let message_id = parse_message_ref(iter.next().ok_or(())?)?;
+                let msg = iter.collect::<Vec<_>>().join(" ");
+                if msg.is_empty() {
+                    Err(())
+                } else {
+                    Ok(Command::Edit { message_id, message: msg })
+                }
+            }
+            Some("/delete") => match (iter.next(), iter.next()) {
+                (Some(id), None) => Ok(Command::Delete(parse_message_ref(id)?)),
+                _ => Err(()),
+            },
+            Some(cmd @ ("/react" | "/unreact")) => match (iter.next(), iter.next(), iter.next()) {
+                (Some(id), Some(emoji), None) => Ok(Command::React { message_id: parse_message_ref(id)?, emoji: emoji.into(), add: cmd == "/react" }),
+                _ => Err(()),
+            },
+            Some("/search") => {
+                let mut filter = SearchFilter::default();
+                let mut words = Vec::new();
+                while let Some(arg) = iter.next() {
+                    match arg {
+                        "--more" => return Ok(Command::SearchMore),
+                        "--from" => filter.author = Some(iter.next().ok_or(())?.to_owned()),
+                        "--since" => filter.since_ago = Some(parse_age(iter.next().ok_or(())?)?),
+                        "--until" => filter.until_ago = Some(parse_age(iter.next().ok_or(())?)?),
+                        word => words.push(word),
+                    }
+                }
+                if !words.is_empty() {
+                    filter.query = Some(words.join(" "));
+                }
+                Ok(Command::Search(filter))
+            }
+            Some(cmd @ ("/mod" | "/unmod")) => match (iter.next(), iter.next()) {
+                (Some(username), None) => {
+                    let role = if cmd == "/mod" { Role::Moderator } else { Role::Member };
+                    Ok(Command::SetRole { username: username.into(), role })
+                }
+                _ => Err(()),
+            },
+            Some("/password") => {
+                let (mut password, mut remove, mut kick_old) = (None, false, false);
+                for arg in iter {
+                    match arg {
+                        "--remove" => remove = true,
+                        "--kick" => kick_old = true,
+                        pw if password.is_none() => password = Some(pw.to_owned()),
+                        _ => return Err(()),
+                    }
+                }
+                // one or the other, so a forgotten argument doesn't quietly open the room
+                if remove == password.is_some() {
+                    return Err(());
+                }
+                Ok(Command::SetPassword { password, kick_old })
+            }
+            Some("/topic") => match iter.collect::<Vec<_>>().join(" ").as_str() {
+                "" => Ok(Command::ShowTopic),
+                "--clear" => Ok(Command::SetTopic(None)),
+                topic => Ok(Command::SetTopic(Some(topic.into()))),
+            },
+            Some("/pins") => Ok(Command::Pins),
+            Some("/invite") => {
+                let (mut expires_in_secs, mut max_uses) = (None, None);
+                while let Some(arg) = iter.next() {
+                    match arg {
+                        "--expires" => expires_in_secs = Some(parse_age(iter.next().ok_or(())?)?),
+                        "--uses" => max_uses = Some(iter.next().and_then(|n| n.parse().ok()).ok_or(())?),
+                        _ => return Err(()),
+                    }
+                }
+                Ok(Command::Invite { expires_in_secs, max_uses })
+            }
+            Some("/invites") => Ok(Command::Invites),
+            Some("/revoke") => match (iter.next(), iter.next()) {
+                (Some(id), None) => Ok(Command::Revoke(id.trim_start_matches('#').parse().map_err(|_| ())?)),
+                _ => Err(()),
+            },
+            Some(cmd @ ("/pin" | "/unpin")) => match (iter.next(), iter.next()) {
+                (Some(id), None) => Ok(Command::Pin { message_id: parse_message_ref(id)?, pinned: cmd == "/pin" }),
+                _ => Err(()),
+            },
+            Some("/more") => Ok(Command::More),
+            Some("/seen") => match (iter.next(), iter.next()) {
+                (None, _) => Ok(Command::Seen(None)),
+                (Some(id), None) => Ok(Command::Seen(parse_message_ref(id)?)),
+                _ => Err(()),
+            },
+            Some("/rooms") => Ok(Command::Rooms),
+            Some("/log") => match (iter.next(), iter.next()) {
+                (Some("on"), None) => Ok(Command::Log(true)),
+                (Some("off"), None) => Ok(Command::Log(false)),
+                _ => Err(()),
+            },
             Some("/leave") => Ok(Command::Leave),
             Some("/exit") => Ok(Command::Exit),
             Some("/help") => Ok(Command::Help),
@@ -91,132 +346,1224 @@ impl FromStr for Command {
     }
 }
 
+#[derive(Default, Deserialize)]
+pub struct SearchFilter {
+    query: Option<String>,
+    author: Option<String>,
+    since_ago: Option<u64>, // seconds before now
+    until_ago: Option<u64>,
+}
+
+// "30m", "2h", "7d" and so on, in seconds
+fn parse_age(arg: &str) -> Result<u64, ()> {
+    let split = arg.find(|c: char| !c.is_ascii_digit()).ok_or(())?;
+    let (amount, unit) = arg.split_at(split);
+    let amount: u64 = amount.parse().map_err(|_| ())?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(()),
+    };
+    amount.checked_mul(unit_secs).ok_or(())
+}
+
+// "#3 (expires Oct 18 14:05, 2/5 uses)"
+fn describe_invite(invite: &InviteInfo) -> String {
+    let mut limits = Vec::new();
+    if let Some(at) = invite.expires_at {
+        limits.push(format!("expires {}", format_time(at)));
+    }
+    match invite.max_uses {
+        Some(max) => limits.push(format!("{}/{} uses", invite.uses, max)),
+        None => limits.push(format!("{} uses", invite.uses)),
+    }
+    format!("#{} ({})", invite.id, limits.join(", "))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn format_time(unix_secs: u64) -> String {
+    match DateTime::from_timestamp(unix_secs as i64, 0) {
+        Some(utc) => utc.with_timezone(&Local).format("%b %d %H:%M").to_string(),
+        None => "?".into(),
+    }
+}
+
+// "last" or a message id as shown in front of each message
+fn parse_message_ref(arg: &str) -> Result<Option<u64>, ()> {
+    if arg == "last" {
+        Ok(None)
+    } else {
+        arg.trim_start_matches('#').parse().map(Some).map_err(|_| ())
+    }
+}
+
+// the last few messages seen in the current chat, so replies can quote their parent
+const RECENT_CAPACITY: usize = 200;
+// read receipts go out at most this often, not once per message
+const MARK_READ_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Default)]
+struct RecentMessages(VecDeque<ChatMessage>);
+
+impl RecentMessages {
+    fn get(&self, id: u64) -> Option<&ChatMessage> {
+        self.0.iter().find(|m| m.id == id)
+    }
+
+    // edits come through here too and replace the old copy
+    fn insert(&mut self, msg: ChatMessage) {
+        if let Some(existing) = self.0.iter_mut().find(|m| m.id == msg.id) {
+            *existing = msg;
+            return;
+        }
+        if self.0.len() == RECENT_CAPACITY {
+            self.0.pop_front();
+        }
+        self.0.push_back(msg);
+    }
+
+    fn newest_id(&self) -> Option<u64> {
+        self.0.iter().map(|m| m.id).max()
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.0.retain(|m| m.id != id);
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+fn snippet(text: &str) -> String {
+    const MAX: usize = 50;
+    if text.chars().count() <= MAX {
+        text.into()
+    } else {
+        format!("{}…", text.chars().take(MAX).collect::<String>())
+    }
+}
+
+// one dimmed line quoting the parent, or just its id if it's older than what we've seen
+fn print_reply_quote(parent_id: u64, recent: &RecentMessages) {
+    let quote = match recent.get(parent_id) {
+        Some(parent) => format!("  ┆ {}: {}", parent.username, snippet(&parent.message)),
+        None => format!("  ┆ reply to #{}", parent_id),
+    };
+    ui_println!("{}", quote.dimmed());
+}
+
+// what a command's error looks like to the user
+fn report(result: Result<(), ClientError>) {
+    match result {
+        Ok(()) => {}
+        Err(ClientError::Server(err)) => {
+            y_println!("[Server] {:?} | {:?}", err.code, err.message);
+        }
+        Err(ClientError::Disconnected) => {
+            y_println!("Not connected to the server, still trying to reconnect");
+        }
+        Err(err) => {
+            y_println!("{}", err);
+        }
+    }
+}
+
+fn print_message(chat: &ChatMessage, recent: &RecentMessages) {
+    if let Some(parent_id) = chat.reply_to {
+        print_reply_quote(parent_id, recent);
+    }
+    ui_println!("{}", format_message(chat));
+}
+
+fn format_message(chat: &ChatMessage) -> String {
+    let edited = if chat.edited { " (edited)".dimmed().to_string() } else { String::new() };
+    format!("{} {}: {}{}{}", format!("#{}", chat.id).dimmed(), chat.username.blue(), chat.message, edited, format_reactions(&chat.reactions))
+}
+
+fn format_reactions(reactions: &[ReactionCount]) -> String {
+    let parts: Vec<String> = reactions.iter().map(|r| format!("{} {}", r.emoji, r.count)).collect();
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!("  [{}]", parts.join("  ")).dimmed().to_string()
+    }
+}
+
+#[derive(Clone)]
+struct Queued {
+    message: String,
+    reply_to: Option<u64>,
+}
+
+// our sends the server hasn't answered yet. the broadcast of one can get here before or after
+// the answer, so while any is unanswered a broadcast under our username waits until its id
+// turns out to be ours or not
+#[derive(Default)]
+struct Pending {
+    next_key: u64, // for the pending line in the ui
+    unanswered: usize,
+    confirmed: HashSet<u64>, // ids of our messages whose broadcast hasn't come yet
+    held: Vec<ChatMessage>,
+}
+
+impl Pending {
+    fn start(&mut self) -> u64 {
+        self.next_key += 1;
+        self.unanswered += 1;
+        self.next_key
+    }
+
+    // our message if its broadcast already came, and anyone else's held back meanwhile once
+    // nothing is unanswered anymore
+    fn answer(&mut self, message_id: Option<u64>) -> (Option<ChatMessage>, Vec<ChatMessage>) {
+        self.unanswered -= 1;
+        let ours = message_id.and_then(|id| match self.held.iter().position(|msg| msg.id == id) {
+            Some(index) => Some(self.held.remove(index)),
+            None => {
+                self.confirmed.insert(id);
+                None
+            }
+        });
+        let released = if self.unanswered == 0 { mem::take(&mut self.held) } else { Vec::new() };
+        (ours, released)
+    }
+
+    // the message back when it's to be shown now. ours is whether it came under our username
+    fn broadcast(&mut self, msg: ChatMessage, ours: bool) -> Option<ChatMessage> {
+        // shown already when the server answered us
+        if self.confirmed.remove(&msg.id) {
+            return None;
+        }
+        // ours or another connection's under the same name, the answer tells which
+        if ours && self.unanswered > 0 {
+            self.held.push(msg);
+            return None;
+        }
+        Some(msg)
+    }
+}
+
+#[derive(Clone)]
 pub struct ChatClient {
-    send_chan: mpsc::UnboundedSender<Packet>,
-    chat_state: Arc<Mutex<Option<(Uuid, Uuid, String)>>>, // (chat_id, token, username)
+    client: Client,
+    last_sent: Arc<Mutex<Option<u64>>>, // id of the last message the server accepted from us
+    recent: Arc<Mutex<RecentMessages>>,
+    last_search: Arc<Mutex<Option<Search>>>,          // already pointing at the next page
+    scrollback: Arc<Mutex<Option<u64>>>,              // before_seq for /more, None once we're at the start
+    read_positions: Arc<Mutex<HashMap<String, u64>>>, // username to the last seq they've read, for /seen
+    known_rooms: Arc<Mutex<Vec<RoomRef>>>,            // every room joined this session, for /rooms
+    topic: Arc<Mutex<Option<String>>>,
+    pins: Arc<Mutex<Vec<ChatMessage>>>,
+    status: Arc<Mutex<Status>>, // connection, members and who's typing, for the full-screen ui
+    queued: Arc<Mutex<VecDeque<Queued>>>, // typed while reconnecting
+    pending: Arc<Mutex<Pending>>,
+    default_username: Option<String>,
+    bookmarks: Arc<Mutex<Bookmarks>>,
+    member_keys: MemberKeys,
+    auto_join: Option<String>,
+    joined_with: Arc<Mutex<Option<JoinedWith>>>, // how we got into the current room, for /bookmark
+    transcript: Arc<Mutex<Transcript>>,
+}
+
+#[derive(Clone)]
+struct JoinedWith {
+    password: Option<String>,
+    invite: Option<String>,
 }
 
 impl ChatClient {
-    pub async fn new(host: String, port: String) -> Result<Self, Box<dyn Error>> {
-        let addr = format!("{}:{}", host, port);
-        let stream = TcpStream::connect(&addr).await?;
+    pub async fn new(settings: &Settings) -> Result<Self, Box<dyn Error>> {
+        let addr = settings.addr.clone();
+        let (client, events) = settings.connect().await?;
 
-        y_println!("Client connected to {}!", addr);
+        if output::is_json() {
+            output::emit("connected", None, json!({ "server": addr }));
+        } else {
+            y_println!("Client connected to {}!", addr);
+        }
 
-        let (read_stream, write_stream) = split(stream);
+        let last_sent = Arc::new(Mutex::new(None::<u64>));
+        let recent = Arc::new(Mutex::new(RecentMessages::default()));
+        let last_search = Arc::new(Mutex::new(None::<Search>));
+        let scrollback = Arc::new(Mutex::new(None::<u64>));
+        let read_positions = Arc::new(Mutex::new(HashMap::<String, u64>::new()));
+        let known_rooms = Arc::new(Mutex::new(Vec::<RoomRef>::new()));
+        let topic = Arc::new(Mutex::new(None::<String>));
+        let pins = Arc::new(Mutex::new(Vec::<ChatMessage>::new()));
+        let status = Arc::new(Mutex::new(Status { server: addr.clone(), connected: true, ..Default::default() }));
 
-        let (send_chan, mut recv_chan) = mpsc::unbounded_channel::<Packet>();
-        let chat_state = Arc::new(Mutex::new(None::<(Uuid, Uuid, String)>));
-        // writer task that communicates with the server
+        // read marker task, anything that made it to the screen counts as read
+        let marker_client = client.clone();
+        let marker_recent = recent.clone();
         tokio::spawn(async move {
-            let mut writer = write_stream;
+            let mut ticker = time::interval(MARK_READ_INTERVAL);
+            let mut marked: Option<(Uuid, u64)> = None; // last (chat_id, seq) sent
 
-            while let Some(pkt) = recv_chan.recv().await {
-                if let Err(e) = write_message(&mut writer, &pkt).await {
-                    debug_r_eprintln!("Write failed: {}", e);
-                    break;
+            loop {
+                ticker.tick().await;
+                let Some(session) = marker_client.session().await else {
+                    continue;
+                };
+                let Some(newest) = marker_recent.lock().await.newest_id() else {
+                    continue;
+                };
+                if marked == Some((session.chat_id, newest)) {
+                    continue;
+                }
+
+                // tried again once we're back
+                if !matches!(marker_client.mark_read(newest).await, Err(ClientError::Disconnected)) {
+                    marked = Some((session.chat_id, newest));
                 }
             }
         });
 
-        // reader task that gets server responses and prints new messages
-        let reader_copy = chat_state.clone();
-        tokio::spawn(async move {
-            let mut reader = read_stream;
-            let chat_state = reader_copy;
+        let this = ChatClient {
+            client,
+            last_sent,
+            recent,
+            last_search,
+            scrollback,
+            read_positions,
+            known_rooms,
+            topic,
+            pins,
+            status,
+            queued: Default::default(),
+            pending: Default::default(),
+            default_username: settings.username.clone(),
+            bookmarks: Arc::new(Mutex::new(settings.bookmarks.clone())),
+            member_keys: settings.member_keys.clone(),
+            auto_join: settings.auto_join.clone(),
+            joined_with: Default::default(),
+            transcript: Arc::new(Mutex::new(Transcript::new(settings.logging.clone()))),
+        };
+        if output::is_json() {
+            // the raw messages say it all, the events task just keeps the state up to date
+            tokio::spawn(output::print_messages(this.client.clone(), this.client.tap()));
+            tokio::spawn(output::quietly(this.clone().handle_events(events)));
+        } else {
+            tokio::spawn(this.clone().handle_events(events));
+        }
+        Ok(this)
+    }
 
-            loop {
-                match read_message(&mut reader).await {
-                    Ok(Packet { message, .. }) => match message {
-                        ProtocolMessage::MessageBroadcast(chat) => {
-                            if let Some((_, _, ref my_username)) = *chat_state.lock().await {
-                                if &chat.username == my_username {
-                                    continue;
-                                }
-                            }
-                            println!("{}: {}", chat.username.blue(), chat.message);
-                        }
-                        ProtocolMessage::CreateChatResponse(resp) => {
-                            y_println!("Created new chat with chat_id = {}", resp.chat_id);
-                        }
-                        ProtocolMessage::JoinChatResponse(resp) => {
-                            y_println!("Joined chat");
-                            debug_println!("token = {}", resp.token);
-                            let mut guard = chat_state.lock().await;
-                            *guard = Some((resp.chat_id, resp.token, resp.username.clone()));
-                        }
-                        ProtocolMessage::LeaveChatResponse(_) => {
-                            y_println!("Left chat");
+    // prints new messages and everything else the room gets told
+    async fn handle_events(self, mut events: Events) {
+        while let Some(event) = events.next().await {
+            match event {
+                Event::Message(chat) => {
+                    self.log_message(&chat).await;
+                    self.status.lock().await.typing.remove(&chat.username);
+                    let ours = self.client.session().await.is_some_and(|session| session.username == chat.username);
+                    // pending before recent, the same as when a send is answered
+                    let mut pending = self.pending.lock().await;
+                    let mut recent = self.recent.lock().await;
+                    recent.insert(chat.clone());
+                    if let Some(chat) = pending.broadcast(chat, ours) {
+                        print_message(&chat, &recent);
+                    }
+                }
+                Event::MessageEdited(chat) => {
+                    self.log_message(&chat).await;
+                    let mut recent = self.recent.lock().await;
+                    recent.insert(chat.clone());
+                    if let Some(pinned) = self.pins.lock().await.iter_mut().find(|m| m.id == chat.id) {
+                        *pinned = chat.clone();
+                    }
+                    print_message(&chat, &recent);
+                }
+                Event::ReactionsUpdated(update) => {
+                    let mut recent = self.recent.lock().await;
+                    let mut line = format!("#{} reactions:", update.message_id);
+                    if let Some(mut msg) = recent.get(update.message_id).cloned() {
+                        line = format!("#{} {}:", msg.id, snippet(&msg.message));
+                        msg.reactions = update.reactions.clone();
+                        recent.insert(msg);
+                    }
+                    if let Some(pinned) = self.pins.lock().await.iter_mut().find(|m| m.id == update.message_id) {
+                        pinned.reactions = update.reactions.clone();
+                    }
+                    let reactions = if update.reactions.is_empty() { "  (none)".dimmed().to_string() } else { format_reactions(&update.reactions) };
+                    ui_println!("{}{}", line.dimmed(), reactions);
+                }
+                Event::MessageDeleted(deleted) => {
+                    self.recent.lock().await.remove(deleted.message_id);
+                    self.pins.lock().await.retain(|m| m.id != deleted.message_id);
+                    y_println!("Message #{} was deleted by {}", deleted.message_id, deleted.deleted_by);
+                }
+                Event::TopicChanged(changed) => {
+                    let line = match changed.topic {
+                        Some(ref topic) => format!("{} set the topic: {}", changed.changed_by, topic),
+                        None => format!("{} cleared the topic", changed.changed_by),
+                    };
+                    y_println!("{}", line);
+                    *self.topic.lock().await = changed.topic;
+                }
+                Event::PinsUpdated(update) => {
+                    let action = if update.pinned { "pinned" } else { "unpinned" };
+                    let what = match update.pins.iter().find(|m| m.id == update.message_id).or(self.recent.lock().await.get(update.message_id)) {
+                        Some(msg) => format!("#{} {}", msg.id, snippet(&msg.message)),
+                        None => format!("#{}", update.message_id),
+                    };
+                    y_println!("{} {} {}", update.changed_by, action, what);
+                    *self.pins.lock().await = update.pins;
+                }
+                Event::PasswordChanged(changed) => {
+                    let mut line = if changed.password_protected {
+                        format!("{} changed the room password", changed.changed_by)
+                    } else {
+                        format!("{} removed the room password", changed.changed_by)
+                    };
+                    if !changed.kicked.is_empty() {
+                        line += &format!(", removing {}", changed.kicked.join(", "));
+                    }
+                    y_println!("{}", line);
+                }
+                Event::TypingUpdated(update) => {
+                    if self.client.session().await.is_some_and(|session| session.username == update.username) {
+                        continue;
+                    }
+                    // in line mode only say so when someone starts, stopping shows up as their message.
+                    // the full-screen ui has it in the status bar instead
+                    let mut status = self.status.lock().await;
+                    if !update.active {
+                        status.typing.remove(&update.username);
+                    } else if status.typing.insert(update.username.clone()) && !ui::is_fullscreen() {
+                        y_println!("{} is typing...", update.username);
+                    }
+                }
+                Event::MemberJoined(member) => {
+                    ui_println!("{}", format!("{} joined", member.username).dimmed());
+                    self.status.lock().await.members.insert(member.username);
+                }
+                Event::MemberLeft(member) => {
+                    ui_println!("{}", format!("{} left", member.username).dimmed());
+                    let mut status = self.status.lock().await;
+                    status.members.remove(&member.username);
+                    status.typing.remove(&member.username);
+                }
+                Event::ReadPositionUpdated(position) => {
+                    self.read_positions.lock().await.insert(position.username, position.seq);
+                }
+                Event::ServerNotice(notice) => {
+                    y_println!("[Server notice] {}", notice.message);
+                }
+                Event::ChatExpiring(expiring) => {
+                    y_println!("This chat will be deleted in {} min: {}", expiring.expires_in_secs.div_ceil(60), expiring.reason);
+                }
+                Event::RemovedFromChat(removed) => {
+                    // the session is already gone if it was for the chat we're in
+                    if self.client.session().await.is_none() {
+                        self.clear_chat().await;
+                    }
+                    y_println!("Removed from chat: {}", removed.reason);
+                }
+                Event::Disconnected(reason) => {
+                    self.local_event("disconnected", json!({ "reason": reason })).await;
+                    r_eprintln!("{}", reason);
+                    let mut status = self.status.lock().await;
+                    status.connected = false;
+                    status.typing.clear();
+                }
+                Event::Reconnecting { attempt, delay } => {
+                    self.local_event("reconnecting", json!({ "attempt": attempt, "delay_secs": delay.as_secs() })).await;
+                    y_println!("Reconnecting in {}s (attempt {})...", delay.as_secs(), attempt);
+                    self.status.lock().await.reconnecting = Some((attempt, Instant::now() + delay));
+                }
+                Event::Reconnected { rejoined } => {
+                    self.local_event("reconnected", json!({ "rejoined": rejoined.as_ref().map(Result::is_ok) })).await;
+                    let mut status = self.status.lock().await;
+                    status.connected = true;
+                    status.reconnecting = None;
+                    y_println!("Reconnected to {}", status.server);
+                    drop(status);
+                    match rejoined {
+                        Some(Ok(resp)) => {
+                            self.rejoined(resp).await;
+                            self.flush_queue().await;
                         }
-                        other => {
-                            if let ProtocolMessage::ErrorResponse(err) = other {
-                                y_println!("[Server] {:?} | {:?}", err.code, err.message);
+                        Some(Err(err)) => {
+                            self.clear_chat().await;
+                            y_println!("Couldn't rejoin the chat: {:?} | {:?}", err.code, err.message);
+                            let mut queued = self.queued.lock().await;
+                            if !queued.is_empty() {
+                                self.local_event("queue_dropped", json!({ "count": queued.len() })).await;
+                                y_println!("{} queued message(s) were not sent", queued.len());
+                                queued.clear();
                             }
                         }
-                    },
-                    Err(e) => {
-                        debug_r_eprintln!("Read error: {}", e);
-                        break;
+                        None => {}
                     }
                 }
             }
-        });
+        }
+    }
 
-        Ok(ChatClient { send_chan, chat_state })
+    // what the full-screen ui needs to draw the status bar and sidebar
+    pub fn ui_shared(&self) -> ui::Shared {
+        ui::Shared { status: self.status.clone(), client: self.client.clone(), topic: self.topic.clone() }
     }
 
-    pub async fn run(&self) -> Result<(), Box<dyn Error>> {
-        let stdin = tokio::io::BufReader::new(tokio::io::stdin());
-        let mut lines_stream = stdin.lines();
+    // tab completion draws on the room's members and the rooms joined so far
+    pub fn completer(&self) -> Completer {
+        Completer { status: self.status.clone(), known_rooms: self.known_rooms.clone(), bookmarks: self.bookmarks.clone() }
+    }
 
-        while let Some(line) = lines_stream.next_line().await? {
-            // straight up magic. I didn't know you could do this with ANSI codes.
-            // basically deletes user input
-            print!("\x1B[1A\x1B[2K");
+    pub async fn run(&self, mut input: InputReader) -> Result<(), Box<dyn Error>> {
+        let mut typing_since: Option<Instant> = None; // when we last told the room we're typing
 
-            // typing /send every time is annoying. if command doesnt start with '/' implicit send mode.
-            let cmd = if line.starts_with('/') { line.parse().unwrap_or(Command::Invalid) } else { Command::Send(line.clone()) };
-            match cmd {
-                Command::Help => {
-                    y_println!("{}", HELP_TEXT);
-                }
-                Command::Create(password) => {
-                    let req = ProtocolMessage::CreateChatRequest(CreateChatRequest { password });
-                    self.send_chan.send(Packet { version: 1, message: req })?;
+        if let Some(alias) = self.auto_join.clone() {
+            report(self.execute(Command::JoinSaved { alias, username: None }).await);
+        }
+
+        while let Some(event) = input.next().await {
+            let line = match event {
+                Input::Line(line) => line,
+                Input::Draft(draft) => {
+                    self.update_typing(&draft, &mut typing_since).await?;
+                    continue;
                 }
-                Command::Join { chat_id, username, password } => {
-                    let req = ProtocolMessage::JoinChatRequest(JoinChatRequest { chat_id, username, password });
-                    self.send_chan.send(Packet { version: 1, message: req })?;
+            };
+
+            // drafts starting with '/' already cleared it, and a sent message clears it on the server
+            typing_since = None;
+
+            let cmd = match Command::from_line(&line) {
+                Ok(cmd) => cmd,
+                Err(err) => {
+                    r_eprintln!("Invalid JSON command: {}", err);
+                    continue;
                 }
-                Command::Send(msg) => {
-                    let guard = self.chat_state.lock().await;
-                    if let Some((chat_id, token, ref username)) = *guard {
-                        println!("{}: {}", username.blue(), msg);
-                        let req = ProtocolMessage::SendMessageRequest(SendMessageRequest { chat_id, token, message: msg });
-                        self.send_chan.send(Packet { version: 1, message: req })?;
-                    } else {
-                        y_println!("You must /join a chat before sending");
+            };
+            if let Command::Exit = cmd {
+                break;
+            }
+            report(self.execute(cmd).await);
+        }
+
+        Ok(())
+    }
+
+    async fn execute(&self, cmd: Command) -> Result<(), ClientError> {
+        match cmd {
+            Command::Help => {
+                y_println!("{}", HELP_TEXT);
+            }
+            Command::Create { password, ttl_minutes } => {
+                // JSON commands skip the parser's check, the server refuses anything too long
+                let ttl_secs = match ttl_minutes.map(|m| m.checked_mul(60)) {
+                    Some(None) => {
+                        y_println!("--ttl is too long");
+                        return Ok(());
                     }
-                }
-                Command::Leave => {
-                    let mut guard = self.chat_state.lock().await;
-                    if let Some((chat_id, token, _)) = *guard {
-                        *guard = None;
-                        let req = ProtocolMessage::LeaveChatRequest(LeaveChatRequest { chat_id, token });
-                        self.send_chan.send(Packet { version: 1, message: req })?;
-                    } else {
-                        y_println!("You are not in a chat");
+                    ttl_secs => ttl_secs.flatten(),
+                };
+                let resp = self.client.create_chat(password, ttl_secs).await?;
+                y_println!("Created new chat with chat_id = {}", resp.chat_id);
+            }
+            Command::Join { chat_id, username, password, invite } => {
+                self.join(chat_id, username, password, invite, None).await?;
+            }
+            Command::JoinSaved { alias, username } => {
+                let saved = match self.bookmarks.lock().await.get(&alias) {
+                    Ok(Some(saved)) => saved,
+                    Ok(None) => {
+                        y_println!("No saved room called @{}", alias);
+                        return Ok(());
+                    }
+                    Err(err) => {
+                        r_eprintln!("Can't read saved room @{}: {}", alias, err);
+                        return Ok(());
+                    }
+                };
+                // a chat id wins over an invite if the config has both
+                let (chat_id, invite) = match (saved.chat_id, saved.invite.clone()) {
+                    (Some(chat_id), _) => (chat_id, None),
+                    (None, Some(invite)) => match InviteCode::decode(&invite) {
+                        Some(code) => (code.chat_id, Some(invite)),
+                        None => {
+                            y_println!("The invite saved for @{} isn't valid", alias);
+                            return Ok(());
+                        }
+                    },
+                    (None, None) => {
+                        y_println!("Saved room @{} has no chat_id or invite", alias);
+                        return Ok(());
                     }
+                };
+                self.join(chat_id, username.or(saved.username.clone()), saved.password.clone(), invite, Some(&saved)).await?;
+            }
+            Command::Bookmark(alias) => {
+                let Some(session) = self.client.session().await else {
+                    y_println!("You must /join a chat before bookmarking it");
+                    return Ok(());
+                };
+                let Some(JoinedWith { password, invite }) = self.joined_with.lock().await.clone() else {
+                    return Ok(());
+                };
+                // an invite is enough to get back in, otherwise it's the chat id and password
+                let room = match invite {
+                    Some(invite) => SavedRoom { chat_id: None, invite: Some(invite), username: Some(session.username), password: None, log: None, log_format: None },
+                    None => SavedRoom { chat_id: Some(session.chat_id), invite: None, username: Some(session.username), password, log: None, log_format: None },
+                };
+                let dropped_password = room.password.is_some() && self.bookmarks.lock().await.storage() == PasswordStorage::None;
+                match self.bookmarks.lock().await.save(&alias, room) {
+                    Ok(()) => {
+                        y_println!("Saved as @{}", alias);
+                    }
+                    Err(err) => {
+                        r_eprintln!("Can't save @{}: {}", alias, err);
+                        return Ok(());
+                    }
+                }
+                if dropped_password {
+                    y_println!("The room password wasn't saved, set password_storage in the config to keep it");
+                }
+            }
+            Command::Bookmarks => {
+                let bookmarks = self.bookmarks.lock().await;
+                if bookmarks.rooms().next().is_none() {
+                    y_println!("No saved rooms, /bookmark <alias> saves the current one");
+                    return Ok(());
+                }
+                for (alias, room) in bookmarks.rooms() {
+                    let target = room.chat_id.map(|id| id.to_string()).or(room.invite.clone()).unwrap_or_default();
+                    let user = room.username.as_ref().map(|user| format!(" as {}", user)).unwrap_or_default();
+                    ui_println!("@{} {}{}", alias.green(), target.dimmed(), user);
+                }
+            }
+            Command::Send(msg) => {
+                self.send_message(msg, None).await?;
+            }
+            Command::Reply { message_id, message } => {
+                self.send_message(message, Some(message_id)).await?;
+            }
+            Command::Thread(message_id) => {
+                if self.client.session().await.is_none() {
+                    y_println!("You are not in a chat");
+                    return Ok(());
+                }
+                let thread = self.client.fetch_thread(message_id).await?;
+                let recent = self.recent.lock().await;
+                y_println!("Thread #{} ({} replies):", thread.root.id, thread.replies.len());
+                print_message(&thread.root, &recent);
+                for reply in &thread.replies {
+                    let ids = format!("#{} ↪ #{}", reply.id, reply.reply_to.unwrap_or(thread.root.id));
+                    let edited = if reply.edited { " (edited)".dimmed().to_string() } else { String::new() };
+                    ui_println!("  {} {}: {}{}", ids.dimmed(), reply.username.blue(), reply.message, edited);
+                }
+            }
+            Command::Edit { message_id, message } => {
+                if self.client.session().await.is_none() {
+                    y_println!("You must /join a chat before editing");
+                    return Ok(());
+                }
+                let Some(message_id) = message_id.or(*self.last_sent.lock().await) else {
+                    y_println!("You haven't sent anything in this chat yet");
+                    return Ok(());
+                };
+                self.client.edit(message_id, message).await?;
+            }
+            Command::Delete(message_id) => {
+                if self.client.session().await.is_none() {
+                    y_println!("You must /join a chat before deleting");
+                    return Ok(());
+                }
+                let Some(message_id) = message_id.or(*self.last_sent.lock().await) else {
+                    y_println!("You haven't sent anything in this chat yet");
+                    return Ok(());
+                };
+                self.client.delete(message_id).await?;
+            }
+            Command::React { message_id, emoji, add } => {
+                if self.client.session().await.is_none() {
+                    y_println!("You are not in a chat");
+                    return Ok(());
+                }
+                let Some(message_id) = message_id.or(*self.last_sent.lock().await) else {
+                    y_println!("You haven't sent anything in this chat yet");
+                    return Ok(());
+                };
+                if add {
+                    self.client.react(message_id, emoji).await?;
+                } else {
+                    self.client.unreact(message_id, emoji).await?;
+                }
+            }
+            Command::SetPassword { password, kick_old } => {
+                if self.client.session().await.is_none() {
+                    y_println!("You are not in a chat");
+                    return Ok(());
+                }
+                self.client.set_password(password.clone(), kick_old).await?;
+                if let Some(joined_with) = self.joined_with.lock().await.as_mut() {
+                    joined_with.password = password;
+                }
+            }
+            Command::ShowTopic => {
+                if self.client.session().await.is_none() {
+                    y_println!("You are not in a chat");
+                    return Ok(());
+                }
+                let line = match *self.topic.lock().await {
+                    Some(ref topic) => format!("Topic: {}", topic),
+                    None => "No topic set".to_owned(),
+                };
+                y_println!("{}", line);
+            }
+            Command::SetTopic(topic) => {
+                if self.client.session().await.is_none() {
+                    y_println!("You are not in a chat");
+                    return Ok(());
+                }
+                self.client.set_topic(topic).await?;
+            }
+            Command::Pins => {
+                if self.client.session().await.is_none() {
+                    y_println!("You are not in a chat");
+                    return Ok(());
+                }
+                // copied out, the events task locks recent before pins
+                let pins = self.pins.lock().await.clone();
+                if pins.is_empty() {
+                    y_println!("No pinned messages");
+                }
+                let recent = self.recent.lock().await;
+                for msg in pins.iter() {
+                    print_message(msg, &recent);
                 }
-                Command::Exit => {
-                    break;
+            }
+            Command::Pin { message_id, pinned } => {
+                if self.client.session().await.is_none() {
+                    y_println!("You are not in a chat");
+                    return Ok(());
+                }
+                let Some(message_id) = message_id.or(*self.last_sent.lock().await) else {
+                    y_println!("You haven't sent anything in this chat yet");
+                    return Ok(());
+                };
+                if pinned {
+                    self.client.pin(message_id).await?;
+                } else {
+                    self.client.unpin(message_id).await?;
+                }
+            }
+            Command::Invite { expires_in_secs, max_uses } => {
+                if self.client.session().await.is_none() {
+                    y_println!("You are not in a chat");
+                    return Ok(());
+                }
+                let resp = self.client.create_invite(expires_in_secs, max_uses).await?;
+                y_println!("Created invite {}, share it with: /join {} <username>", describe_invite(&resp.invite), resp.invite.code);
+            }
+            Command::Invites => {
+                if self.client.session().await.is_none() {
+                    y_println!("You are not in a chat");
+                    return Ok(());
+                }
+                let resp = self.client.list_invites().await?;
+                if resp.invites.is_empty() {
+                    y_println!("No open invites");
+                }
+                for invite in resp.invites {
+                    ui_println!("{} by {}: {}", describe_invite(&invite), invite.created_by, invite.code);
+                }
+            }
+            Command::Revoke(invite_id) => {
+                if self.client.session().await.is_none() {
+                    y_println!("You are not in a chat");
+                    return Ok(());
+                }
+                self.client.revoke_invite(invite_id).await?;
+                y_println!("Invite revoked");
+            }
+            Command::Search(filter) => {
+                if self.client.session().await.is_none() {
+                    y_println!("You must /join a chat before searching");
+                    return Ok(());
+                }
+                let now = unix_now();
+                let search = Search {
+                    query: filter.query,
+                    author: filter.author,
+                    since: filter.since_ago.map(|ago| now.saturating_sub(ago)),
+                    until: filter.until_ago.map(|ago| now.saturating_sub(ago)),
+                    ..Default::default()
+                };
+                self.search(search).await?;
+            }
+            Command::SearchMore => {
+                let Some(search) = self.last_search.lock().await.clone() else {
+                    y_println!("No more results");
+                    return Ok(());
+                };
+                self.search(search).await?;
+            }
+            Command::More => {
+                if self.client.session().await.is_none() {
+                    y_println!("You are not in a chat");
+                    return Ok(());
+                }
+                let Some(before_seq) = *self.scrollback.lock().await else {
+                    y_println!("No older messages");
+                    return Ok(());
+                };
+                let resp = self.client.fetch_history(Some(before_seq), None).await?;
+                // older than anything on screen, so it gets its own block instead of mixing in
+                y_println!("── {} older message(s) ──", resp.messages.len());
+                let recent = self.recent.lock().await;
+                for msg in &resp.messages {
+                    print_message(msg, &recent);
+                }
+                y_println!("──────────");
+                if resp.next_before_seq.is_none() {
+                    y_println!("That's the beginning of this chat");
+                }
+                *self.scrollback.lock().await = resp.next_before_seq;
+            }
+            Command::Seen(message_id) => {
+                let Some(session) = self.client.session().await else {
+                    y_println!("You are not in a chat");
+                    return Ok(());
+                };
+                let Some(message_id) = message_id.or(*self.last_sent.lock().await) else {
+                    y_println!("You haven't sent anything in this chat yet");
+                    return Ok(());
+                };
+                let positions = self.read_positions.lock().await;
+                let mut seen_by: Vec<&str> = positions.iter().filter(|(name, seq)| **name != session.username && **seq >= message_id).map(|(name, _)| name.as_str()).collect();
+                seen_by.sort();
+                if seen_by.is_empty() {
+                    y_println!("Nobody else has seen #{} yet", message_id);
+                } else {
+                    y_println!("#{} seen by {}", message_id, seen_by.join(", "));
                 }
-                Command::Invalid => {
-                    y_println!("Invalid command. Type /help to see correct syntax");
+            }
+            Command::Rooms => {
+                let rooms = self.known_rooms.lock().await.clone();
+                if rooms.is_empty() {
+                    y_println!("You haven't joined any rooms yet");
+                    return Ok(());
+                }
+                let resp = self.client.list_rooms(rooms).await?;
+                if resp.rooms.is_empty() {
+                    y_println!("None of the rooms you've joined are still around");
+                }
+                for room in resp.rooms {
+                    let last = room.last_message_at.map(format_time).unwrap_or_else(|| "never".into());
+                    ui_println!("{} — {} member(s), {} unread, last message {}", room.chat_id, room.members, room.unread, last);
                 }
             }
+            Command::Log(true) => match self.transcript.lock().await.start() {
+                Ok(path) => {
+                    y_println!("Logging this room to {}", path.display());
+                }
+                Err(err) => {
+                    r_eprintln!("Can't log this room: {}", err);
+                }
+            },
+            Command::Log(false) => {
+                if self.transcript.lock().await.stop() {
+                    y_println!("Stopped logging this room");
+                } else {
+                    y_println!("This room isn't being logged");
+                }
+            }
+            Command::SetRole { username, role } => {
+                if self.client.session().await.is_none() {
+                    y_println!("You are not in a chat");
+                    return Ok(());
+                }
+                self.client.set_role(username, role).await?;
+                y_println!("Role updated");
+            }
+            Command::Leave => {
+                if self.client.session().await.is_none() {
+                    y_println!("You are not in a chat");
+                    return Ok(());
+                }
+                self.clear_chat().await;
+                match self.client.leave().await {
+                    // the server let go of us when the connection dropped
+                    Ok(_) | Err(ClientError::Disconnected) => {
+                        y_println!("Left chat");
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            Command::Exit => {}
+            Command::Invalid => {
+                y_println!("Invalid command. Type /help to see correct syntax");
+            }
         }
+        Ok(())
+    }
 
+    async fn join(&self, chat_id: Uuid, username: Option<String>, password: Option<String>, invite: Option<String>, saved: Option<&SavedRoom>) -> Result<(), ClientError> {
+        let Some(username) = username.or(self.default_username.clone()) else {
+            y_println!("No username given and there's no default username in the config");
+            return Ok(());
+        };
+        if let Err(err) = self.member_keys.load_into(&self.client, chat_id, &username).await {
+            r_eprintln!("Can't read member keys: {}", err);
+        }
+        let resp = match invite.clone() {
+            Some(invite) => self.client.join_with_invite(invite, username).await?,
+            None => self.client.join(chat_id, username, password.clone()).await?,
+        };
+        if let Err(err) = self.member_keys.save(resp.chat_id, &resp.username, resp.member_key) {
+            r_eprintln!("Can't save the member key, this name won't be yours after a restart: {}", err);
+        }
+        *self.joined_with.lock().await = Some(JoinedWith { password, invite });
+        if let Err(err) = self.transcript.lock().await.joined(resp.chat_id, saved) {
+            r_eprintln!("Can't log this room: {}", err);
+        }
+        self.joined(resp).await;
         Ok(())
     }
+
+    async fn joined(&self, resp: JoinChatResponse) {
+        let as_role = match resp.role {
+            Role::Owner => " as its owner",
+            Role::Moderator => " as a moderator",
+            Role::Member => "",
+        };
+        y_println!("Joined chat{}", as_role);
+        debug_println!("token = {}", resp.token);
+
+        if let Some(ref topic) = resp.topic {
+            y_println!("Topic: {}", topic);
+        }
+        if !resp.pins.is_empty() {
+            y_println!("{} pinned message(s), see /pins", resp.pins.len());
+        }
+        *self.topic.lock().await = resp.topic;
+        *self.pins.lock().await = resp.pins;
+
+        let mut status = self.status.lock().await;
+        status.members = resp.members.into_iter().collect();
+        status.typing.clear();
+        drop(status);
+        let mut recent = self.recent.lock().await;
+        recent.clear();
+        if resp.next_before_seq.is_some() {
+            y_println!("(older messages with /more)");
+        }
+        // marker goes above the first message after where we left off last time
+        let mut unread_after = resp.last_read_seq.filter(|_| resp.unread > 0);
+        for msg in resp.history {
+            if unread_after.is_some_and(|seq| msg.id > seq) {
+                y_println!("── {} unread ──", resp.unread);
+                unread_after = None;
+            }
+            print_message(&msg, &recent);
+            recent.insert(msg);
+        }
+        *self.scrollback.lock().await = resp.next_before_seq;
+        *self.read_positions.lock().await = resp.read_positions.into_iter().map(|p| (p.username, p.seq)).collect();
+
+        let mut known_rooms = self.known_rooms.lock().await;
+        known_rooms.retain(|room| room.chat_id != resp.chat_id);
+        known_rooms.push(RoomRef { chat_id: resp.chat_id, username: resp.username, member_key: resp.member_key });
+    }
+
+    // after a reconnect, only what came in while we were away gets printed
+    async fn rejoined(&self, resp: JoinChatResponse) {
+        y_println!("Rejoined chat");
+        *self.topic.lock().await = resp.topic;
+        *self.pins.lock().await = resp.pins;
+
+        let mut status = self.status.lock().await;
+        status.members = resp.members.into_iter().collect();
+        status.typing.clear();
+        drop(status);
+        let mut recent = self.recent.lock().await;
+        let newest = recent.newest_id();
+        // what came in while we were away goes in the transcript like any other new message
+        for msg in resp.history {
+            if newest.is_none_or(|id| msg.id > id) {
+                self.log_message(&msg).await;
+                print_message(&msg, &recent);
+            }
+            recent.insert(msg);
+        }
+        *self.read_positions.lock().await = resp.read_positions.into_iter().map(|p| (p.username, p.seq)).collect();
+    }
+
+    // things that happen on this end, for --output json
+    async fn local_event(&self, kind: &str, payload: Value) {
+        if output::is_json() {
+            output::emit(kind, self.client.session().await.map(|session| session.chat_id), payload);
+        }
+    }
+
+    // everything that belongs to the chat we were in
+    async fn clear_chat(&self) {
+        self.transcript.lock().await.left();
+        let mut pending = self.pending.lock().await;
+        pending.confirmed.clear();
+        pending.held.clear();
+        drop(pending);
+        *self.last_sent.lock().await = None;
+        self.recent.lock().await.clear();
+        *self.last_search.lock().await = None;
+        *self.scrollback.lock().await = None;
+        self.read_positions.lock().await.clear();
+        *self.topic.lock().await = None;
+        self.pins.lock().await.clear();
+        let mut status = self.status.lock().await;
+        status.members.clear();
+        status.typing.clear();
+    }
+
+    async fn log_message(&self, msg: &ChatMessage) {
+        if let Err(err) = self.transcript.lock().await.write(msg) {
+            r_eprintln!("Stopped logging this room: {}", err);
+        }
+    }
+
+    // sends what was typed while reconnecting, in order. one at a time so anything typed
+    // meanwhile still queues up behind it
+    async fn flush_queue(&self) {
+        loop {
+            let Some(Queued { message, reply_to }) = self.queued.lock().await.front().cloned() else {
+                return;
+            };
+            let Some(session) = self.client.session().await else {
+                return;
+            };
+            // its queued line stays, the answer is printed below it
+            let key = self.pending.lock().await.start();
+            let sent = match reply_to {
+                Some(parent_id) => self.client.reply(parent_id, message.clone()).await,
+                None => self.client.send(message.clone()).await,
+            };
+            if let Err(ClientError::Disconnected) = sent {
+                // still queued for the next reconnect
+                let (_, released) = self.pending.lock().await.answer(None);
+                self.print_released(released).await;
+                return;
+            }
+            let _ = self.answered(key, &session.username, message, reply_to, sent).await;
+            self.queued.lock().await.pop_front();
+        }
+    }
+
+    async fn search(&self, search: Search) -> Result<(), ClientError> {
+        let resp = self.client.search(search.clone()).await?;
+        if resp.results.is_empty() {
+            y_println!("No matching messages");
+        }
+        let recent = self.recent.lock().await;
+        for msg in &resp.results {
+            if let Some(parent_id) = msg.reply_to {
+                print_reply_quote(parent_id, &recent);
+            }
+            ui_println!("{} {}", format!("[{}]", format_time(msg.sent_at)).dimmed(), format_message(msg));
+        }
+
+        *self.last_search.lock().await = resp.next_before_id.map(|next| Search { before_id: Some(next), ..search });
+        if resp.next_before_id.is_some() {
+            y_println!("More results with /search --more");
+        }
+        Ok(())
+    }
+
+    // a draft that would be sent as a message counts as typing, commands don't. refreshed
+    // well before the server would drop it, and cleared when the draft is emptied
+    async fn update_typing(&self, draft: &str, typing_since: &mut Option<Instant>) -> Result<(), ClientError> {
+        if self.client.session().await.is_none() {
+            return Ok(());
+        }
+
+        let active = !draft.trim().is_empty() && !draft.starts_with('/');
+        let refresh_due = typing_since.is_none_or(|since| since.elapsed() >= TYPING_TIMEOUT / 2);
+        if (active && !refresh_due) || (!active && typing_since.is_none()) {
+            return Ok(());
+        }
+
+        *typing_since = active.then(Instant::now);
+        match self.client.typing(active).await {
+            Err(ClientError::NotInChat | ClientError::Disconnected) => Ok(()), // left or lost the connection in the meantime
+            result => result,
+        }
+    }
+
+    async fn send_message(&self, message: String, reply_to: Option<u64>) -> Result<(), ClientError> {
+        let Some(session) = self.client.session().await else {
+            y_println!("You must /join a chat before sending");
+            return Ok(());
+        };
+        if let Some(parent_id) = reply_to {
+            print_reply_quote(parent_id, &*self.recent.lock().await);
+        }
+
+        // held until we're back in the chat, behind anything already waiting
+        let mut queued = self.queued.lock().await;
+        if !self.client.is_connected() || !queued.is_empty() {
+            ui_println!("{}: {} {}", session.username.blue(), message, "(queued)".dimmed());
+            self.local_event("message_queued", json!({ "message": message, "reply_to": reply_to })).await;
+            queued.push_back(Queued { message, reply_to });
+            return Ok(());
+        }
+        drop(queued);
+
+        let key = self.pending.lock().await.start();
+        ui::print_pending(key, format!("{}: {} {}", session.username.blue(), message, "(sending)".dimmed()));
+        self.local_event("message_pending", json!({ "local_id": key, "message": message, "reply_to": reply_to })).await;
+        let sent = match reply_to {
+            Some(parent_id) => self.client.reply(parent_id, message.clone()).await,
+            None => self.client.send(message.clone()).await,
+        };
+        self.answered(key, &session.username, message, reply_to, sent).await
+    }
+
+    // replaces the pending line with the message as everyone else sees it, or marks it failed
+    async fn answered(&self, key: u64, username: &str, message: String, reply_to: Option<u64>, sent: Result<SendMessageResponse, ClientError>) -> Result<(), ClientError> {
+        let mut pending = self.pending.lock().await;
+        let (ours, released) = pending.answer(sent.as_ref().ok().map(|resp| resp.message_id));
+        drop(pending);
+
+        let result = match sent {
+            Ok(resp) => {
+                let msg = ours.unwrap_or(ChatMessage { id: resp.message_id, sent_at: unix_now(), username: username.into(), message, edited: false, reply_to, reactions: Vec::new() });
+                ui::resolve_pending(key, format_message(&msg));
+                self.local_event("message_sent", json!({ "local_id": key, "message_id": resp.message_id })).await;
+                *self.last_sent.lock().await = Some(resp.message_id);
+                Ok(())
+            }
+            Err(err) => {
+                ui::resolve_pending(key, format!("{}: {} {}", username.blue(), message, "(not sent)".red()));
+                self.local_event("message_failed", json!({ "local_id": key, "error": err.to_string() })).await;
+                Err(err)
+            }
+        };
+        self.print_released(released).await;
+        result
+    }
+
+    // broadcasts under our username that weren't ours after all
+    async fn print_released(&self, released: Vec<ChatMessage>) {
+        let recent = self.recent.lock().await;
+        for msg in &released {
+            print_message(msg, &recent);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_secrets_out_of_history() {
+        let invite = InviteCode { chat_id: Uuid::new_v4(), invite_id: 1, mac: [0; protocol::INVITE_MAC_LEN] }.encode();
+        let chat_id = Uuid::new_v4();
+        for line in [format!("/join {chat_id} bob hunter2"), format!("/join {invite} bob"), format!("/join {invite}"), "/create hunter2".into(), "/password hunter2".into()] {
+            assert!(line.parse::<Command>().unwrap().has_secret(), "{line}");
+        }
+        for line in [format!("/join {chat_id} bob"), "/create".into(), "/password --remove".into(), "/send hunter2".into()] {
+            assert!(!line.parse::<Command>().unwrap().has_secret(), "{line}");
+        }
+    }
+
+    #[test]
+    fn keeps_secret_json_commands_out_of_history() {
+        output::set_json();
+        assert!(Command::is_secret_line(r#"{"command":"join","args":{"chat_id":"6f1c0e52-2f4e-4a8e-9d3e-0d1c2b3a4f5e","username":"bob","password":"pw"}}"#));
+        assert!(Command::is_secret_line(r#"{"command":"join","args":{"invite":"abc","username":"bob"}}"#));
+        assert!(Command::is_secret_line(r#"{"command":"set_password","args":{"password":"pw","kick_old":false}}"#));
+        assert!(Command::is_secret_line(r#"{"command":"join","args":{"password":"pw""#), "can't be read");
+        assert!(Command::is_secret_line("/join not-a-room bob pw"), "can't be read");
+        assert!(!Command::is_secret_line(r#"{"command":"send","args":"hello"}"#));
+        assert!(!Command::is_secret_line("/send hello"));
+        assert!(!Command::is_secret_line("hello"));
+    }
+
+    #[test]
+    fn parses_ages() {
+        assert_eq!(parse_age("45s"), Ok(45));
+        assert_eq!(parse_age("30m"), Ok(30 * 60));
+        assert_eq!(parse_age("2h"), Ok(2 * 60 * 60));
+        assert_eq!(parse_age("7d"), Ok(7 * 24 * 60 * 60));
+        assert_eq!(parse_age("1w"), Ok(7 * 24 * 60 * 60));
+        assert_eq!(parse_age("0m"), Ok(0));
+    }
+
+    #[test]
+    fn rejects_bad_ages() {
+        for arg in ["", "30", "m", "30x", "-5m", "1.5h", "30 m", "3mm"] {
+            assert_eq!(parse_age(arg), Err(()), "{arg:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_ages_that_overflow() {
+        assert_eq!(parse_age(&format!("{}s", u64::MAX)), Ok(u64::MAX));
+        assert_eq!(parse_age(&format!("{}m", u64::MAX)), Err(()));
+        assert_eq!(parse_age(&format!("{}w", u64::MAX / (7 * 24 * 60 * 60) + 1)), Err(()));
+        assert_eq!(parse_age("99999999999999999999s"), Err(()));
+    }
+
+    fn message(id: u64) -> ChatMessage {
+        ChatMessage { id, sent_at: 0, username: "alice".into(), message: format!("message {id}"), edited: false, reply_to: None, reactions: Vec::new() }
+    }
+
+    // the ids are enough to tell the messages apart
+    fn answered(pending: &mut Pending, message_id: Option<u64>) -> (Option<u64>, Vec<u64>) {
+        let (ours, released) = pending.answer(message_id);
+        (ours.map(|msg| msg.id), released.iter().map(|msg| msg.id).collect())
+    }
+
+    #[test]
+    fn broadcast_before_the_answer() {
+        let mut pending = Pending::default();
+        pending.start();
+        assert!(pending.broadcast(message(5), true).is_none(), "held until the answer");
+        assert_eq!(answered(&mut pending, Some(5)), (Some(5), Vec::new()));
+        assert!(pending.held.is_empty() && pending.confirmed.is_empty());
+    }
+
+    #[test]
+    fn broadcast_after_the_answer() {
+        let mut pending = Pending::default();
+        pending.start();
+        assert_eq!(answered(&mut pending, Some(5)), (None, Vec::new()));
+        assert!(pending.broadcast(message(5), true).is_none(), "shown already with the answer");
+        assert!(pending.confirmed.is_empty());
+        assert_eq!(pending.broadcast(message(6), true).map(|msg| msg.id), Some(6), "nothing unanswered, nothing to wait for");
+    }
+
+    #[test]
+    fn failed_send_releases_what_it_held() {
+        let mut pending = Pending::default();
+        pending.start();
+        // another connection under our name
+        assert!(pending.broadcast(message(7), true).is_none());
+        assert_eq!(answered(&mut pending, None), (None, vec![7]));
+        assert!(pending.held.is_empty() && pending.confirmed.is_empty());
+    }
+
+    #[test]
+    fn holds_messages_while_a_send_is_unanswered() {
+        let mut pending = Pending::default();
+        pending.start();
+        pending.start();
+        assert!(pending.broadcast(message(3), true).is_none());
+        assert!(pending.broadcast(message(4), true).is_none());
+        assert_eq!(pending.broadcast(message(5), false).map(|msg| msg.id), Some(5), "only our username waits");
+
+        assert_eq!(answered(&mut pending, Some(4)), (Some(4), Vec::new()), "3 waits for the other answer");
+        assert_eq!(answered(&mut pending, Some(6)), (None, vec![3]));
+        assert!(pending.broadcast(message(6), true).is_none());
+        assert!(pending.held.is_empty() && pending.confirmed.is_empty());
+    }
 }
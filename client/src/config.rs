@@ -0,0 +1,117 @@
+// config.rs
+// ~/.config/clique/config.toml: named server profiles, a default username, colors and saved
+// rooms. everything in it is optional and a missing file is the same as an empty one, the
+// command line wins over whatever a profile says. /bookmark writes to it, see bookmarks.rs
+
+use std::{collections::BTreeMap, env, fs, io, path::PathBuf};
+
+use cliqueclient::TlsOptions;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    bookmarks::{Bookmarks, PasswordStorage},
+    member_keys::MemberKeys,
+    transcript::{LogFormat, Logging},
+};
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub default_profile: Option<String>,
+    pub username: Option<String>,
+    pub colors: Option<bool>,
+    pub profiles: BTreeMap<String, Profile>,
+    pub rooms: BTreeMap<String, SavedRoom>, // by alias, for /join @alias
+    pub auto_join: Option<String>,          // alias of a saved room to join on startup
+    pub password_storage: PasswordStorage,  // where /bookmark puts room passwords
+    pub logging: Logging,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub username: Option<String>, // instead of the top-level one
+    pub tls: bool,
+    pub server_name: Option<String>,
+    pub ca_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SavedRoom {
+    pub chat_id: Option<Uuid>, // either this or an invite
+    pub invite: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub log: Option<bool>, // instead of [logging]'s enabled and format
+    pub log_format: Option<LogFormat>,
+}
+
+// where to connect and as who, once the profile and command line are taken into account
+pub struct Settings {
+    pub addr: String,
+    pub tls: Option<TlsOptions>,
+    pub username: Option<String>,
+    pub colors: bool,
+    pub bookmarks: Bookmarks,
+    pub member_keys: MemberKeys,
+    pub auto_join: Option<String>,
+    pub logging: Logging,
+}
+
+// $XDG_CONFIG_HOME/clique/config.toml, falling back to ~/.config
+pub fn default_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from).or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("clique").join("config.toml"))
+}
+
+impl Config {
+    pub fn load(path: Option<PathBuf>) -> Result<Self, String> {
+        let Some(path) = path.or_else(default_path) else {
+            return Ok(Config::default());
+        };
+        let mut config: Config = match fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        config.path = Some(path);
+        Ok(config)
+    }
+
+    pub fn settings(self, profile: Option<String>, host: Option<String>, port: Option<u16>) -> Result<Settings, String> {
+        let profile = match profile.or(self.default_profile) {
+            Some(name) => self.profiles.get(&name).cloned().ok_or(format!("no profile named {} in the config", name))?,
+            None => Profile::default(),
+        };
+        let host = host.or(profile.host).unwrap_or_else(|| "127.0.0.1".into());
+        let port = port.or(profile.port).unwrap_or(8080);
+        let tls = profile.tls.then_some(TlsOptions { server_name: profile.server_name, ca_file: profile.ca_file });
+
+        let addr = format!("{}:{}", host, port);
+        Ok(Settings {
+            member_keys: MemberKeys::new(self.path.as_ref(), &addr),
+            addr,
+            tls,
+            username: profile.username.or(self.username),
+            colors: self.colors.unwrap_or(true),
+            bookmarks: Bookmarks::new(self.rooms, self.path, self.password_storage),
+            auto_join: self.auto_join,
+            logging: self.logging,
+        })
+    }
+}
+
+impl Settings {
+    pub async fn connect(&self) -> io::Result<(cliqueclient::Client, cliqueclient::Events)> {
+        match &self.tls {
+            Some(tls) => cliqueclient::connect_tls(&self.addr, tls.clone()).await,
+            None => cliqueclient::connect(&self.addr).await,
+        }
+    }
+}
@@ -0,0 +1,350 @@
+// editor.rs
+// the line being typed: cursor movement, readline-style kill/yank, history that's kept
+// between sessions and tab completion. both the plain terminal reader and the full-screen
+// ui read crossterm's key events and turn them into a Key with Key::from_event
+
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use protocol::RoomRef;
+use ratatui::crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use tokio::sync::Mutex;
+
+use crate::{bookmarks::Bookmarks, client::Command, ui::Status};
+
+// lines kept in the history file, older ones are dropped when it's loaded
+const HISTORY_LIMIT: usize = 1000;
+const HISTORY_FILE: &str = ".clique_history";
+
+pub enum Key {
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    WordLeft,
+    WordRight,
+    Home,
+    End,
+    Up,   // older history
+    Down, // newer history
+    KillWord,
+    KillToStart,
+    KillToEnd,
+    Yank,
+    Interrupt,
+    Eof,
+}
+
+impl Key {
+    // None for releases and keys the editor has no use for
+    pub fn from_event(key: &KeyEvent) -> Option<Key> {
+        if key.kind == KeyEventKind::Release {
+            return None;
+        }
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+        Some(match key.code {
+            KeyCode::Char(c) if ctrl => match c {
+                'a' => Key::Home,
+                'e' => Key::End,
+                'b' => Key::Left,
+                'f' => Key::Right,
+                'p' => Key::Up,
+                'n' => Key::Down,
+                'w' => Key::KillWord,
+                'u' => Key::KillToStart,
+                'k' => Key::KillToEnd,
+                'y' => Key::Yank,
+                'c' => Key::Interrupt,
+                'd' => Key::Eof,
+                _ => return None,
+            },
+            KeyCode::Char('b') if alt => Key::WordLeft,
+            KeyCode::Char('f') if alt => Key::WordRight,
+            KeyCode::Char(c) => Key::Char(c),
+            KeyCode::Backspace if alt => Key::KillWord,
+            KeyCode::Backspace => Key::Backspace,
+            KeyCode::Delete => Key::Delete,
+            KeyCode::Left if ctrl || alt => Key::WordLeft,
+            KeyCode::Right if ctrl || alt => Key::WordRight,
+            KeyCode::Left => Key::Left,
+            KeyCode::Right => Key::Right,
+            KeyCode::Home => Key::Home,
+            KeyCode::End => Key::End,
+            KeyCode::Up => Key::Up,
+            KeyCode::Down => Key::Down,
+            KeyCode::Tab => Key::Tab,
+            KeyCode::Enter => Key::Enter,
+            _ => return None,
+        })
+    }
+}
+
+pub enum Edit {
+    Nothing,
+    Moved,   // only the cursor, the text is the same
+    Changed, // the text changed
+    Submit(String),
+    Candidates(Vec<String>), // a second tab with several ways to complete, for the caller to show
+    Quit,
+}
+
+// where completions come from, the client keeps these up to date
+#[derive(Clone)]
+pub struct Completer {
+    pub status: Arc<Mutex<Status>>,
+    pub known_rooms: Arc<Mutex<Vec<RoomRef>>>,
+    pub bookmarks: Arc<Mutex<Bookmarks>>,
+}
+
+impl Completer {
+    // commands in the first word, chat ids and @aliases after /join, usernames everywhere else
+    async fn candidates(&self, before: &str, word: &str) -> Vec<String> {
+        let mut words = before.split_whitespace();
+        let mut candidates: Vec<String> = match (words.next(), words.next()) {
+            (None, _) if word.starts_with('/') => Command::NAMES.iter().map(|name| name.to_string()).collect(),
+            (Some("/join"), None) => {
+                let mut rooms: Vec<String> = self.known_rooms.lock().await.iter().map(|room| room.chat_id.to_string()).collect();
+                rooms.extend(self.bookmarks.lock().await.aliases().map(|alias| format!("@{}", alias)));
+                rooms
+            }
+            _ => self.status.lock().await.members.iter().cloned().collect(),
+        };
+        candidates.retain(|candidate| candidate.starts_with(word));
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+}
+
+pub struct LineEditor {
+    line: Vec<char>,
+    cursor: usize,
+    killed: Vec<char>, // last thing cut with ctrl-w/u/k, for ctrl-y
+    history: History,
+    tabbed: bool, // the last key was a tab that couldn't complete any further
+    completer: Completer,
+}
+
+impl LineEditor {
+    pub fn new(completer: Completer) -> Self {
+        LineEditor { line: Vec::new(), cursor: 0, killed: Vec::new(), history: History::load(), tabbed: false, completer }
+    }
+
+    pub fn text(&self) -> String {
+        self.line.iter().collect()
+    }
+
+    // the text split at the cursor
+    pub fn split(&self) -> (String, String) {
+        (self.line[..self.cursor].iter().collect(), self.line[self.cursor..].iter().collect())
+    }
+
+    pub async fn key(&mut self, key: Key) -> Edit {
+        let tabbed = std::mem::take(&mut self.tabbed);
+        match key {
+            Key::Char(c) => {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+                Edit::Changed
+            }
+            Key::Enter => {
+                let line = self.text();
+                self.history.push(&line);
+                self.line.clear();
+                self.cursor = 0;
+                Edit::Submit(line)
+            }
+            Key::Tab => self.complete(tabbed).await,
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+                Edit::Changed
+            }
+            Key::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+                Edit::Changed
+            }
+            Key::Left if self.cursor > 0 => self.move_to(self.cursor - 1),
+            Key::Right if self.cursor < self.line.len() => self.move_to(self.cursor + 1),
+            Key::WordLeft => self.move_to(self.word_start()),
+            Key::WordRight => self.move_to(self.word_end()),
+            Key::Home => self.move_to(0),
+            Key::End => self.move_to(self.line.len()),
+            Key::Up => match self.history.older(&self.line) {
+                Some(line) => self.replace(line),
+                None => Edit::Nothing,
+            },
+            Key::Down => match self.history.newer() {
+                Some(line) => self.replace(line),
+                None => Edit::Nothing,
+            },
+            Key::KillWord => self.kill(self.word_start(), self.cursor),
+            Key::KillToStart => self.kill(0, self.cursor),
+            Key::KillToEnd => self.kill(self.cursor, self.line.len()),
+            Key::Yank if !self.killed.is_empty() => {
+                let killed = self.killed.clone();
+                self.line.splice(self.cursor..self.cursor, killed.iter().copied());
+                self.cursor += killed.len();
+                Edit::Changed
+            }
+            Key::Interrupt => Edit::Quit,
+            // like a shell, ctrl-d quits on an empty line and deletes forward otherwise
+            Key::Eof if self.line.is_empty() => Edit::Quit,
+            Key::Eof if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+                Edit::Changed
+            }
+            _ => Edit::Nothing,
+        }
+    }
+
+    fn move_to(&mut self, cursor: usize) -> Edit {
+        if cursor == self.cursor {
+            return Edit::Nothing;
+        }
+        self.cursor = cursor;
+        Edit::Moved
+    }
+
+    fn replace(&mut self, line: Vec<char>) -> Edit {
+        self.cursor = line.len();
+        self.line = line;
+        Edit::Changed
+    }
+
+    fn kill(&mut self, from: usize, to: usize) -> Edit {
+        if from == to {
+            return Edit::Nothing;
+        }
+        self.killed = self.line.drain(from..to).collect();
+        self.cursor = from;
+        Edit::Changed
+    }
+
+    // start of the word the cursor is in or just after
+    fn word_start(&self) -> usize {
+        let mut i = self.cursor;
+        while i > 0 && self.line[i - 1].is_whitespace() {
+            i -= 1;
+        }
+        while i > 0 && !self.line[i - 1].is_whitespace() {
+            i -= 1;
+        }
+        i
+    }
+
+    fn word_end(&self) -> usize {
+        let mut i = self.cursor;
+        while i < self.line.len() && self.line[i].is_whitespace() {
+            i += 1;
+        }
+        while i < self.line.len() && !self.line[i].is_whitespace() {
+            i += 1;
+        }
+        i
+    }
+
+    // one match is filled in, several get their common prefix filled in and are
+    // listed on the next tab
+    async fn complete(&mut self, tabbed: bool) -> Edit {
+        let mut start = self.cursor;
+        while start > 0 && !self.line[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        let before: String = self.line[..start].iter().collect();
+        let word: String = self.line[start..self.cursor].iter().collect();
+
+        let candidates = self.completer.candidates(&before, &word).await;
+        let completion = match candidates.as_slice() {
+            [] => return Edit::Nothing,
+            [only] => format!("{} ", only),
+            [first, rest @ ..] => rest.iter().fold(first.clone(), |prefix, candidate| {
+                prefix.chars().zip(candidate.chars()).take_while(|(a, b)| a == b).map(|(a, _)| a).collect()
+            }),
+        };
+
+        if completion.chars().count() > word.chars().count() {
+            let added: Vec<char> = completion.chars().skip(word.chars().count()).collect();
+            self.line.splice(self.cursor..self.cursor, added.iter().copied());
+            self.cursor += added.len();
+            return Edit::Changed;
+        }
+        if tabbed {
+            return Edit::Candidates(candidates);
+        }
+        self.tabbed = true;
+        Edit::Nothing
+    }
+}
+
+struct History {
+    entries: Vec<String>,
+    browsing: Option<usize>, // index of the entry shown, None when on the line being typed
+    draft: Vec<char>,        // the line being typed, put back after browsing past the newest entry
+    file: Option<PathBuf>,
+}
+
+impl History {
+    // ~/.clique_history, no history file at all when there's no home directory
+    fn load() -> Self {
+        let file = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+        let mut entries: Vec<String> = file.as_ref().and_then(|file| fs::read_to_string(file).ok()).unwrap_or_default().lines().map(str::to_owned).collect();
+        if entries.len() > HISTORY_LIMIT {
+            entries.drain(..entries.len() - HISTORY_LIMIT);
+            if let Some(file) = &file {
+                let _ = fs::write(file, entries.iter().map(|entry| format!("{}\n", entry)).collect::<String>());
+            }
+        }
+        History { entries, browsing: None, draft: Vec::new(), file }
+    }
+
+    fn push(&mut self, line: &str) {
+        self.browsing = None;
+        if line.trim().is_empty() || self.entries.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.entries.push(line.to_owned());
+
+        // passwords stay in this session's history but never go to disk
+        if Command::is_secret_line(line) {
+            return;
+        }
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = OpenOptions::new().create(true).append(true).mode(0o600).open(file) {
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+    }
+
+    fn older(&mut self, current: &[char]) -> Option<Vec<char>> {
+        let index = match self.browsing {
+            None => {
+                self.draft = current.to_vec();
+                self.entries.len().checked_sub(1)?
+            }
+            Some(index) => index.checked_sub(1)?,
+        };
+        self.browsing = Some(index);
+        Some(self.entries[index].chars().collect())
+    }
+
+    fn newer(&mut self) -> Option<Vec<char>> {
+        let index = self.browsing? + 1;
+        if index == self.entries.len() {
+            self.browsing = None;
+            return Some(std::mem::take(&mut self.draft));
+        }
+        self.browsing = Some(index);
+        Some(self.entries[index].chars().collect())
+    }
+}
@@ -0,0 +1,180 @@
+// input.rs
+// stdin, one event per finished line. on a terminal the line is put together here
+// key by key instead of by the tty, so the client can tell while something is being typed
+// and the line can be edited
+
+use std::{
+    io::{stderr, stdout, IsTerminal, Write},
+    sync::{Mutex as SyncMutex, PoisonError},
+    thread,
+};
+
+use ratatui::crossterm::{
+    event::{self, Event},
+    terminal,
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::mpsc,
+};
+
+use crate::editor::{Edit, Key, LineEditor};
+
+// the line being typed in --plain mode split at the cursor, Some while raw mode is on
+static PROMPT: SyncMutex<Option<(String, String)>> = SyncMutex::new(None);
+
+pub enum Input {
+    Line(String),
+    Draft(String), // the unfinished line changed, only on a terminal
+}
+
+pub struct InputReader {
+    events: mpsc::UnboundedReceiver<Input>,
+    _terminal: Option<Box<dyn Send>>, // puts the terminal back when the reader goes away
+}
+
+impl InputReader {
+    pub fn new(editor: LineEditor) -> Self {
+        let (tx, events) = mpsc::unbounded_channel();
+
+        // piped input keeps working line by line like before
+        let raw = if std::io::stdin().is_terminal() { RawMode::enable() } else { None };
+        if raw.is_some() {
+            tokio::spawn(read_keys(tx, editor));
+        } else {
+            tokio::spawn(read_lines(tx));
+        }
+
+        InputReader { events, _terminal: raw.map(|raw| Box::new(raw) as Box<dyn Send>) }
+    }
+
+    // for input that comes from somewhere else, like the full-screen ui
+    pub fn from_events(events: mpsc::UnboundedReceiver<Input>, terminal: impl Send + 'static) -> Self {
+        InputReader { events, _terminal: Some(Box::new(terminal)) }
+    }
+
+    // None once stdin is closed
+    pub async fn next(&mut self) -> Option<Input> {
+        self.events.recv().await
+    }
+}
+
+async fn read_lines(tx: mpsc::UnboundedSender<Input>) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        // straight up magic. I didn't know you could do this with ANSI codes.
+        // basically deletes user input. only on a terminal, it's garbage to anything reading a pipe
+        if stdout().is_terminal() {
+            print!("\x1B[1A\x1B[2K");
+        }
+        if tx.send(Input::Line(line)).is_err() {
+            break;
+        }
+    }
+}
+
+// crossterm's read blocks, so keys are read on a plain thread and handed over like in the
+// full-screen ui. whatever's queued up is handled together, a paste is one draft and one redraw
+async fn read_keys(tx: mpsc::UnboundedSender<Input>, mut editor: LineEditor) {
+    let (key_tx, mut key_rx) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        while let Ok(ev) = event::read() {
+            if key_tx.send(ev).is_err() {
+                break;
+            }
+        }
+    });
+
+    while let Some(ev) = key_rx.recv().await {
+        let mut events = vec![ev];
+        while let Ok(ev) = key_rx.try_recv() {
+            events.push(ev);
+        }
+
+        let mut changed = false;
+        for ev in events {
+            let Some(key) = (match ev {
+                Event::Key(key) => Key::from_event(&key),
+                _ => None,
+            }) else {
+                continue;
+            };
+            match editor.key(key).await {
+                Edit::Nothing | Edit::Moved => {}
+                Edit::Changed => changed = true,
+                Edit::Submit(line) => {
+                    draw_prompt(&editor);
+                    if tx.send(Input::Line(line)).is_err() {
+                        return;
+                    }
+                    changed = true;
+                }
+                Edit::Candidates(candidates) => write_line(&candidates.join("  "), false),
+                // ctrl-c, and ctrl-d on an empty line, quit like the end of piped input would
+                Edit::Quit => return,
+            }
+        }
+        draw_prompt(&editor);
+
+        if changed && tx.send(Input::Draft(editor.text())).is_err() {
+            return;
+        }
+    }
+}
+
+// a finished line of output. while keys are being read the line being typed is cleared first and
+// drawn again underneath, and raw mode leaves turning \n into \r\n to us
+pub fn write_line(line: &str, to_stderr: bool) {
+    let prompt = PROMPT.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some((before, after)) = prompt.as_ref() else {
+        if to_stderr {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+        return;
+    };
+
+    let mut out = stdout().lock();
+    let _ = write!(out, "\r\x1B[2K");
+    let text = format!("{}\r\n", line.replace('\n', "\r\n"));
+    if to_stderr {
+        let _ = out.flush();
+        let _ = stderr().write_all(text.as_bytes());
+    } else {
+        let _ = out.write_all(text.as_bytes());
+    }
+    // the whole line, then again up to the cursor to leave it in the right column
+    let _ = write!(out, "{}{}\r{}", before, after, before);
+    let _ = out.flush();
+}
+
+fn draw_prompt(editor: &LineEditor) {
+    let mut prompt = PROMPT.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(prompt) = prompt.as_mut() else {
+        return;
+    };
+    *prompt = editor.split();
+    let (before, after) = &*prompt;
+    let mut out = stdout().lock();
+    let _ = write!(out, "\r\x1B[2K{}{}\r{}", before, after, before);
+    let _ = out.flush();
+}
+
+// the terminal hands over every key as it's pressed, without echoing it
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> Option<Self> {
+        terminal::enable_raw_mode().ok()?;
+        *PROMPT.lock().unwrap_or_else(PoisonError::into_inner) = Some(Default::default());
+        Some(RawMode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        *PROMPT.lock().unwrap_or_else(PoisonError::into_inner) = None;
+        let _ = terminal::disable_raw_mode();
+    }
+}
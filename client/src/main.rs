@@ -1,13 +1,89 @@
 // main.rs
 
+mod bookmarks;
 mod client;
-use std::{env, error::Error};
+mod config;
+mod editor;
+mod input;
+mod member_keys;
+mod output;
+mod script;
+mod transcript;
+mod ui;
+use std::{
+    error::Error,
+    io::{stdin, stdout, IsTerminal},
+    path::PathBuf,
+    process,
+};
+
+use clap::{Parser, ValueEnum};
+use config::Config;
+use editor::LineEditor;
+use input::InputReader;
+use script::Script;
+
+#[derive(Parser)]
+#[command(name = "client", version, about = "Terminal client for CLIque chat servers")]
+struct Args {
+    #[arg(help = "Server to connect to [default: the profile's, or 127.0.0.1]")]
+    host: Option<String>,
+    #[arg(help = "Port to connect to [default: the profile's, or 8080]")]
+    port: Option<u16>,
+    #[arg(long, help = "Server profile from the config file [default: its default_profile]")]
+    profile: Option<String>,
+    #[arg(long, help = "Config file [default: ~/.config/clique/config.toml]")]
+    config: Option<PathBuf>,
+    #[arg(long, help = "Line-by-line output instead of the full-screen view")]
+    plain: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Output::Text,
+        help = "json prints everything received and every local event as one JSON object per line, and reads lines starting with '{' as JSON commands"
+    )]
+    output: Output,
+    #[command(flatten)]
+    script: Script,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Output {
+    Text,
+    Json,
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let mut args = env::args().skip(1);
-    let host = args.next().unwrap_or_else(|| "127.0.0.1".into());
-    let port = args.next().unwrap_or_else(|| "8080".into());
-    let client = client::ChatClient::new(host, port).await?;
-    client.run().await
+async fn main() {
+    // plain messages rather than Debug output, scripts check the exit code
+    if let Err(err) = run(Args::parse()).await {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    let settings = Config::load(args.config)?.settings(args.profile, args.host, args.port)?;
+    if !settings.colors {
+        colored::control::set_override(false);
+    }
+
+    let mut script = args.script;
+    let json = args.output == Output::Json;
+    if script.requested() {
+        // only where there's something to print, --json on its own is still an error
+        script.json |= json && script.listen;
+        return script::run(&settings, script).await;
+    }
+
+    if json {
+        output::set_json();
+    }
+    // the full-screen ui needs a terminal on both ends, anything piped gets line mode
+    let plain = args.plain || json || !stdin().is_terminal() || !stdout().is_terminal();
+    let client = client::ChatClient::new(&settings).await?;
+
+    let editor = LineEditor::new(client.completer());
+    let input = if plain { InputReader::new(editor) } else { ui::start(client.ui_shared(), editor)? };
+    client.run(input).await
 }
@@ -0,0 +1,120 @@
+// member_keys.rs
+// the key the server hands out with every join, saved next to the config so an owner or moderator
+// who restarts the client gets their name back. without it the name is locked to a key nobody has
+// and the room is left without an owner. written like the passwords file, readable by us only
+
+use std::{fs, io, path::PathBuf};
+
+use cliqueclient::Client;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::bookmarks::write_private;
+
+const MEMBER_KEYS_FILE: &str = "member_keys";
+// rooms don't outlive the server, the oldest keys go once there are this many
+const MAX_KEYS: usize = 500;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+struct Entry {
+    server: String,
+    chat_id: Uuid,
+    username: String,
+    key: Uuid,
+}
+
+#[derive(Clone)]
+pub struct MemberKeys {
+    path: Option<PathBuf>, // None when there's no config directory
+    server: String,
+}
+
+impl MemberKeys {
+    pub fn new(config_path: Option<&PathBuf>, server: &str) -> Self {
+        MemberKeys { path: config_path.map(|path| path.with_file_name(MEMBER_KEYS_FILE)), server: server.to_owned() }
+    }
+
+    // oldest first. no file is no keys
+    fn read(&self) -> Result<Vec<Entry>, String> {
+        let Some(path) = &self.path else {
+            return Ok(Vec::new());
+        };
+        match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn get(&self, chat_id: Uuid, username: &str) -> Result<Option<Uuid>, String> {
+        let entries = self.read()?;
+        Ok(entries.iter().rev().find(|entry| entry.server == self.server && entry.chat_id == chat_id && entry.username == username).map(|entry| entry.key))
+    }
+
+    pub fn save(&self, chat_id: Uuid, username: &str, key: Uuid) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut entries = self.read()?;
+        let entry = Entry { server: self.server.clone(), chat_id, username: username.to_owned(), key };
+        if entries.last() == Some(&entry) {
+            return Ok(());
+        }
+        entries.retain(|old| !(old.server == entry.server && old.chat_id == chat_id && old.username == username));
+        entries.push(entry);
+        if entries.len() > MAX_KEYS {
+            entries.drain(..entries.len() - MAX_KEYS);
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        let data = serde_json::to_vec(&entries).map_err(|e| e.to_string())?;
+        write_private(path, &data)
+    }
+
+    // hands the client the key from an earlier run before it joins under this name
+    pub async fn load_into(&self, client: &Client, chat_id: Uuid, username: &str) -> Result<(), String> {
+        if let Some(key) = self.get(chat_id, username)? {
+            client.remember_member_key(chat_id, username.to_owned(), key).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, os::unix::fs::PermissionsExt};
+
+    use super::*;
+
+    #[test]
+    fn keys_are_per_server_room_and_name() {
+        let dir = env::temp_dir().join(format!("clique-member-keys-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = dir.join("config.toml");
+        let (keys, other_server) = (MemberKeys::new(Some(&config), "a:8080"), MemberKeys::new(Some(&config), "b:8080"));
+        let (chat_id, key, newer) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        assert_eq!(keys.get(chat_id, "alice"), Ok(None), "no file is no keys");
+        keys.save(chat_id, "alice", key).unwrap();
+        assert_eq!(keys.get(chat_id, "alice"), Ok(Some(key)));
+        assert_eq!(keys.get(chat_id, "bob"), Ok(None));
+        assert_eq!(keys.get(Uuid::new_v4(), "alice"), Ok(None));
+        assert_eq!(other_server.get(chat_id, "alice"), Ok(None));
+
+        keys.save(chat_id, "alice", newer).unwrap();
+        assert_eq!(keys.get(chat_id, "alice"), Ok(Some(newer)));
+        assert_eq!(keys.read().unwrap().len(), 1, "replaced, not added");
+
+        let path = dir.join(MEMBER_KEYS_FILE);
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let mut full = keys.read().unwrap();
+        full.extend((1..MAX_KEYS).map(|_| Entry { server: "a:8080".into(), chat_id: Uuid::new_v4(), username: "alice".into(), key: Uuid::new_v4() }));
+        fs::write(&path, serde_json::to_vec(&full).unwrap()).unwrap();
+        keys.save(Uuid::new_v4(), "alice", Uuid::new_v4()).unwrap();
+        assert_eq!(keys.read().unwrap().len(), MAX_KEYS);
+        assert_eq!(keys.get(chat_id, "alice"), Ok(None), "the oldest dropped off");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,64 @@
+// output.rs
+// --output json: one JSON object per line instead of colored text. everything the server sends
+// is printed as it arrives, the client's own lines become notices and errors, and a few local
+// events (connection drops, reconnects) get their own type
+
+use std::{
+    future::Future,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use cliqueclient::Client;
+use protocol::ProtocolMessage;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::input;
+
+static JSON: OnceLock<()> = OnceLock::new();
+
+tokio::task_local! {
+    // around the events task, whose lines only restate messages that were already printed raw
+    static QUIET: ();
+}
+
+pub fn set_json() {
+    let _ = JSON.set(());
+    colored::control::set_override(false);
+}
+
+pub fn is_json() -> bool {
+    JSON.get().is_some()
+}
+
+pub async fn quietly<F: Future>(future: F) -> F::Output {
+    QUIET.scope((), future).await
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.try_with(|_| ()).is_ok()
+}
+
+// {"type", "chat_id", "timestamp", "payload"}, timestamp in unix milliseconds
+pub fn emit(kind: &str, chat_id: Option<Uuid>, payload: Value) {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+    input::write_line(&json!({ "type": kind, "chat_id": chat_id, "timestamp": timestamp, "payload": payload }).to_string(), false);
+}
+
+// prints what the server sends, tagged with the chat it's about or the one we're in
+pub async fn print_messages(client: Client, mut messages: mpsc::UnboundedReceiver<ProtocolMessage>) {
+    while let Some(message) = messages.recv().await {
+        let kind = message.kind();
+        let payload = match serde_json::to_value(message) {
+            Ok(Value::Object(mut tagged)) => tagged.remove("body").unwrap_or(Value::Null),
+            _ => Value::Null,
+        };
+        let chat_id = match payload.get("chat_id").and_then(Value::as_str).and_then(|id| Uuid::parse_str(id).ok()) {
+            Some(chat_id) => Some(chat_id),
+            None => client.session().await.map(|session| session.chat_id),
+        };
+        emit(kind, chat_id, payload);
+    }
+}
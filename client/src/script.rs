@@ -0,0 +1,113 @@
+// script.rs
+// non-interactive use from shell pipelines: join a room, send a message or whatever comes in
+// on stdin and exit, or print the room's messages as they arrive. nothing is colored, stdout
+// only gets what another program would want to read
+
+use std::error::Error;
+
+use cliqueclient::{ClientError, Event};
+use protocol::InviteCode;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use uuid::Uuid;
+
+use crate::config::Settings;
+
+#[derive(clap::Args)]
+#[command(next_help_heading = "Scripting (any of these skips the interactive client)")]
+pub struct Script {
+    #[arg(long, value_name = "ID|INVITE|@ALIAS", help = "Join this room")]
+    pub room: Option<String>,
+    #[arg(long, help = "Create a room and join it, its chat id is printed")]
+    pub create: bool,
+    #[arg(long, help = "Username to join as [default: the config's]")]
+    pub user: Option<String>,
+    #[arg(long, help = "Room password, also sets it on a room made with --create")]
+    pub password: Option<String>,
+    #[arg(long, help = "Send this and exit, otherwise each line on stdin is sent")]
+    pub message: Option<String>,
+    #[arg(long, help = "Print the room's messages as they arrive instead of exiting")]
+    pub listen: bool,
+    #[arg(long, help = "With --listen, one JSON object per message")]
+    pub json: bool,
+}
+
+impl Script {
+    // any of these means there's no interactive client
+    pub fn requested(&self) -> bool {
+        self.room.is_some() || self.create || self.message.is_some() || self.listen
+    }
+}
+
+pub async fn run(settings: &Settings, mut script: Script) -> Result<(), Box<dyn Error>> {
+    // a saved room fills in whatever wasn't given on the command line
+    if let Some(alias) = script.room.as_deref().and_then(|room| room.strip_prefix('@')) {
+        let saved = settings.bookmarks.get(alias)?.ok_or(format!("no saved room called @{}", alias))?;
+        script.room = Some(saved.chat_id.map(|id| id.to_string()).or(saved.invite.clone()).ok_or(format!("saved room @{} has no chat_id or invite", alias))?);
+        script.user = script.user.or(saved.username.clone());
+        script.password = script.password.or(saved.password.clone());
+    }
+    let Some(username) = script.user.or(settings.username.clone()) else {
+        return Err("--user is required with --room or --create, or set a username in the config".into());
+    };
+    if script.json && !script.listen {
+        return Err("--json only applies to --listen".into());
+    }
+    let (client, mut events) = settings.connect().await?;
+
+    let (chat_id, invite) = match (script.room, script.create) {
+        (Some(_), true) => return Err("--room and --create can't be used together".into()),
+        (Some(room), false) => match (Uuid::parse_str(&room), InviteCode::decode(&room)) {
+            (Ok(chat_id), _) => (chat_id, None),
+            (Err(_), Some(code)) => (code.chat_id, Some(room)),
+            (Err(_), None) => return Err(format!("{} is neither a chat id nor an invite code", room).into()),
+        },
+        (None, true) => {
+            let created = client.create_chat(script.password.clone(), None).await?;
+            // on stdout so `$(client --create ...)` gets it, unless that's where messages go
+            if script.listen {
+                eprintln!("{}", created.chat_id);
+            } else {
+                println!("{}", created.chat_id);
+            }
+            (created.chat_id, None)
+        }
+        (None, false) => return Err("--room or --create is needed to send or listen".into()),
+    };
+    // an owner or moderator keeps their name from one run to the next
+    settings.member_keys.load_into(&client, chat_id, &username).await?;
+    let joined = match invite {
+        Some(invite) => client.join_with_invite(invite, username).await?,
+        None => client.join(chat_id, username, script.password).await?,
+    };
+    settings.member_keys.save(joined.chat_id, &joined.username, joined.member_key)?;
+
+    // stdin is only read when there's nothing else to send and nothing to listen for
+    if let Some(message) = script.message {
+        client.send(message).await?;
+    } else if !script.listen {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Some(line) = lines.next_line().await? {
+            if !line.trim().is_empty() {
+                client.send(line).await?;
+            }
+        }
+    }
+
+    if !script.listen {
+        client.leave().await?;
+        return Ok(());
+    }
+
+    // until the room goes away, a dropped connection is reconnected like in the interactive client
+    while let Some(event) = events.next().await {
+        match event {
+            Event::Message(msg) if script.json => println!("{}", serde_json::to_string(&msg)?),
+            Event::Message(msg) => println!("{}: {}", msg.username, msg.message),
+            Event::RemovedFromChat(removed) => return Err(format!("removed from chat: {}", removed.reason).into()),
+            Event::Disconnected(reason) => eprintln!("{}, reconnecting", reason),
+            Event::Reconnected { rejoined: Some(Err(err)) } => return Err(ClientError::Server(err).into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
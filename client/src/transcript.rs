@@ -0,0 +1,231 @@
+// transcript.rs
+// a local record of the rooms we're in, one file per room that's rotated once it gets big.
+// [logging] in the config turns it on for every room, a bookmark's log setting wins over that
+// and /log turns it on or off for the room we're in
+
+use std::{
+    env,
+    fs::{self, File},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local};
+use protocol::ChatMessage;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::config::SavedRoom;
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json, // one message per line, like --listen --json
+}
+
+impl LogFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Logging {
+    pub enabled: bool,
+    pub format: LogFormat,
+    pub dir: Option<PathBuf>, // defaults to $XDG_DATA_HOME/clique/logs
+    pub max_size: u64,        // bytes before the file is rotated
+    pub keep: u32,            // rotated files kept around, <chat_id>.log.1 is the newest
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Logging { enabled: false, format: LogFormat::Text, dir: None, max_size: 1024 * 1024, keep: 5 }
+    }
+}
+
+// $XDG_DATA_HOME/clique/logs, falling back to ~/.local/share
+fn default_dir() -> Option<PathBuf> {
+    let base = env::var_os("XDG_DATA_HOME").map(PathBuf::from).or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share")))?;
+    Some(base.join("clique").join("logs"))
+}
+
+struct RoomLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+pub struct Transcript {
+    settings: Logging,
+    room: Option<(Uuid, LogFormat)>, // the room we're in and how its log is written
+    log: Option<RoomLog>,
+}
+
+impl Transcript {
+    pub fn new(settings: Logging) -> Self {
+        Transcript { settings, room: None, log: None }
+    }
+
+    // starts logging a room we just joined if the config or its bookmark asks for it
+    pub fn joined(&mut self, chat_id: Uuid, saved: Option<&SavedRoom>) -> Result<(), String> {
+        let enabled = saved.and_then(|saved| saved.log).unwrap_or(self.settings.enabled);
+        let format = saved.and_then(|saved| saved.log_format).unwrap_or(self.settings.format);
+        self.room = Some((chat_id, format));
+        self.log = None;
+        if enabled {
+            self.start()?;
+        }
+        Ok(())
+    }
+
+    pub fn left(&mut self) {
+        self.room = None;
+        self.log = None;
+    }
+
+    // appends to whatever was logged for the room before
+    pub fn start(&mut self) -> Result<PathBuf, String> {
+        let (chat_id, _) = self.room.ok_or("You are not in a chat")?;
+        if let Some(log) = &self.log {
+            return Ok(log.path.clone());
+        }
+        let dir = self.settings.dir.clone().or_else(default_dir).ok_or("no directory for logs, set dir under [logging] in the config")?;
+        fs::create_dir_all(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        let path = dir.join(format!("{}.log", chat_id));
+        let file = open(&path)?;
+        let size = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+        self.log = Some(RoomLog { path: path.clone(), file, size });
+        Ok(path)
+    }
+
+    // false if it wasn't on
+    pub fn stop(&mut self) -> bool {
+        self.log.take().is_some()
+    }
+
+    // new messages and edits as they arrive. on an error logging stops, so it's only reported once
+    pub fn write(&mut self, msg: &ChatMessage) -> Result<(), String> {
+        let (Some(log), Some((_, format))) = (self.log.as_mut(), self.room) else {
+            return Ok(());
+        };
+        let line = match format {
+            LogFormat::Text => format_line(msg),
+            LogFormat::Json => serde_json::to_string(msg).map_err(|e| e.to_string())?,
+        } + "\n";
+
+        let written = (|| -> Result<(), String> {
+            if log.size > 0 && log.size + line.len() as u64 > self.settings.max_size {
+                rotate(&log.path, self.settings.keep)?;
+                log.file = open(&log.path)?;
+                log.size = 0;
+            }
+            log.file.write_all(line.as_bytes()).map_err(|e| format!("{}: {}", log.path.display(), e))?;
+            log.size += line.len() as u64;
+            Ok(())
+        })();
+        if written.is_err() {
+            self.log = None;
+        }
+        written
+    }
+}
+
+// "[2026-10-18 15:23:01] #12 ↪ #9 alice: sounds good (edited)", in local time
+fn format_line(msg: &ChatMessage) -> String {
+    let time = DateTime::from_timestamp(msg.sent_at as i64, 0).map(|utc| utc.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string()).unwrap_or("?".into());
+    let reply = msg.reply_to.map(|parent| format!(" ↪ #{}", parent)).unwrap_or_default();
+    let edited = if msg.edited { " (edited)" } else { "" };
+    format!("[{}] #{}{} {}: {}{}", time, msg.id, reply, msg.username, msg.message, edited)
+}
+
+// <chat_id>.log becomes .log.1, .log.1 becomes .log.2 and so on, the oldest falls off the end
+fn rotate(path: &Path, keep: u32) -> Result<(), String> {
+    let numbered = |n: u32| PathBuf::from(format!("{}.{}", path.display(), n));
+    if keep == 0 {
+        return fs::remove_file(path).map_err(|e| format!("{}: {}", path.display(), e));
+    }
+    let _ = fs::remove_file(numbered(keep));
+    for n in (1..keep).rev() {
+        let _ = fs::rename(numbered(n), numbered(n + 1));
+    }
+    fs::rename(path, numbered(1)).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// readable by us only, the same as the config and passwords file
+fn open(path: &Path) -> Result<File, String> {
+    fs::OpenOptions::new().append(true).create(true).mode(0o600).open(path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("clique-transcript-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn message(id: u64, text: &str) -> ChatMessage {
+        ChatMessage { id, sent_at: 0, username: "alice".into(), message: text.into(), edited: false, reply_to: None, reactions: Vec::new() }
+    }
+
+    fn read(path: PathBuf) -> Option<String> {
+        fs::read_to_string(path).ok()
+    }
+
+    #[test]
+    fn rotating_drops_the_oldest() {
+        let dir = temp_dir("rotate");
+        let path = dir.join("room.log");
+        for (name, contents) in [("room.log", "newest"), ("room.log.1", "older"), ("room.log.2", "oldest")] {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+        rotate(&path, 2).unwrap();
+        assert_eq!(read(path), None);
+        assert_eq!(read(dir.join("room.log.1")).as_deref(), Some("newest"));
+        assert_eq!(read(dir.join("room.log.2")).as_deref(), Some("older"));
+        assert_eq!(read(dir.join("room.log.3")), None, "past keep, gone");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn keeping_none_just_removes_the_file() {
+        let dir = temp_dir("keep-none");
+        let path = dir.join("room.log");
+        fs::write(&path, "newest").unwrap();
+        rotate(&path, 0).unwrap();
+        assert_eq!(read(path), None);
+        assert_eq!(read(dir.join("room.log.1")), None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rotates_before_going_over_max_size() {
+        let dir = temp_dir("max-size");
+        let chat_id = Uuid::new_v4();
+        let mut transcript = Transcript::new(Logging { enabled: true, format: LogFormat::Text, dir: Some(dir.clone()), max_size: 16, keep: 1 });
+        transcript.joined(chat_id, None).unwrap();
+        let path = dir.join(format!("{}.log", chat_id));
+
+        // an empty file takes the line even if it's over, otherwise it would never be written
+        transcript.write(&message(1, "longer than max_size on its own")).unwrap();
+        assert!(read(path.clone()).unwrap().contains("#1 alice: longer than max_size on its own"));
+        assert_eq!(read(dir.join(format!("{}.log.1", chat_id))), None);
+
+        transcript.write(&message(2, "next")).unwrap();
+        assert!(read(dir.join(format!("{}.log.1", chat_id))).unwrap().contains("#1 alice"));
+        let current = read(path).unwrap();
+        assert!(current.contains("#2 alice: next") && !current.contains("#1"), "{current}");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,341 @@
+// ui.rs
+// full-screen interface: messages on the left, members on the right, a status bar and the input
+// line at the bottom. everything the client prints goes through print_line, which writes to
+// stdout in --plain mode and to the message pane otherwise
+
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    sync::{Arc, Mutex as SyncMutex, OnceLock},
+    thread,
+    time::Duration,
+};
+
+use ansi_to_tui::IntoText;
+use cliqueclient::Client;
+use colored::Colorize;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::{Line, Span, Text},
+    widgets::{Block, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+use tokio::{
+    sync::{mpsc, Mutex},
+    time,
+};
+use uuid::Uuid;
+
+use crate::{
+    editor::{Edit, Key, LineEditor},
+    input::{self, Input, InputReader},
+    output,
+};
+
+// lines kept in the message pane, older ones fall off the top
+const SCROLLBACK_LIMIT: usize = 2000;
+const SIDEBAR_WIDTH: u16 = 24;
+// the status bar has nothing pushing redraws when it changes, so it's also redrawn on a timer
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+
+static PANE: OnceLock<mpsc::UnboundedSender<PaneLine>> = OnceLock::new();
+
+// a pending line is one of our messages the server hasn't answered yet, it's replaced in place
+// by the resolved one once it has
+enum PaneLine {
+    Line(String),
+    Pending(u64, String),
+    Resolved(u64, String),
+}
+
+pub fn print_line(line: String) {
+    if output::is_json() {
+        if !output::is_quiet() {
+            output::emit("notice", None, serde_json::json!({ "text": line }));
+        }
+        return;
+    }
+    match PANE.get() {
+        Some(pane) => {
+            let _ = pane.send(PaneLine::Line(line));
+        }
+        None => input::write_line(&line, false),
+    }
+}
+
+// the client emits its own events for these with --output json
+pub fn print_pending(key: u64, line: String) {
+    if output::is_json() {
+        return;
+    }
+    match PANE.get() {
+        Some(pane) => {
+            let _ = pane.send(PaneLine::Pending(key, line));
+        }
+        None => input::write_line(&line, false),
+    }
+}
+
+// without the full-screen ui there's nothing to replace, the resolved line comes after it
+pub fn resolve_pending(key: u64, line: String) {
+    if output::is_json() {
+        return;
+    }
+    match PANE.get() {
+        Some(pane) => {
+            let _ = pane.send(PaneLine::Resolved(key, line));
+        }
+        None => input::write_line(&line, false),
+    }
+}
+
+pub fn print_error(line: String) {
+    if output::is_json() {
+        if !output::is_quiet() {
+            output::emit("error", None, serde_json::json!({ "text": line }));
+        }
+        return;
+    }
+    match PANE.get() {
+        Some(pane) => {
+            let _ = pane.send(PaneLine::Line(line));
+        }
+        None => input::write_line(&line, true),
+    }
+}
+
+pub fn is_fullscreen() -> bool {
+    PANE.get().is_some()
+}
+
+// what the status bar and sidebar show, kept up to date by the client's reader task
+#[derive(Default, Clone)]
+pub struct Status {
+    pub server: String,
+    pub connected: bool,
+    pub reconnecting: Option<(u32, time::Instant)>, // attempt and when it's made
+    pub members: BTreeSet<String>,
+    pub typing: BTreeSet<String>,
+}
+
+// the rest of what's on screen already lives on ChatClient, these are handles to it
+pub struct Shared {
+    pub status: Arc<Mutex<Status>>,
+    pub client: Client, // for the session, which room we're in and as who
+    pub topic: Arc<Mutex<Option<String>>>,
+}
+
+// takes over the terminal until the returned reader is dropped
+pub fn start(shared: Shared, editor: LineEditor) -> Result<InputReader, Box<dyn Error>> {
+    let terminal = Arc::new(SyncMutex::new(Some(ratatui::try_init()?)));
+    let (pane_tx, pane_rx) = mpsc::unbounded_channel();
+    PANE.set(pane_tx).map_err(|_| "ui already started")?;
+
+    // crossterm's read blocks, so keys are read on a plain thread and handed over
+    let (key_tx, key_rx) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        while let Ok(ev) = event::read() {
+            if key_tx.send(ev).is_err() {
+                break;
+            }
+        }
+    });
+
+    let (input_tx, input_rx) = mpsc::unbounded_channel();
+    tokio::spawn(run(terminal.clone(), shared, editor, pane_rx, key_rx, input_tx));
+
+    Ok(InputReader::from_events(input_rx, TerminalGuard(terminal)))
+}
+
+struct TerminalGuard(Arc<SyncMutex<Option<DefaultTerminal>>>);
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        // taking it out stops the ui task from drawing over the restored terminal
+        if let Ok(mut terminal) = self.0.lock() {
+            if terminal.take().is_some() {
+                ratatui::restore();
+            }
+        }
+    }
+}
+
+struct App {
+    lines: Vec<(Option<u64>, Line<'static>)>, // tagged with their key while pending
+    editor: LineEditor,
+    scroll: usize, // lines up from the bottom, 0 follows new messages
+}
+
+impl App {
+    fn push(&mut self, line: &str, pending: Option<u64>) {
+        let text = line.into_text().unwrap_or_else(|_| Text::raw(line.to_owned()));
+        self.lines.extend(text.lines.into_iter().map(|line| (pending, line)));
+        if self.lines.len() > SCROLLBACK_LIMIT {
+            self.lines.drain(..self.lines.len() - SCROLLBACK_LIMIT);
+        }
+    }
+
+    // a pending line that's already scrolled off just gets the resolved one at the bottom
+    fn resolve(&mut self, key: u64, line: &str) {
+        let Some(start) = self.lines.iter().position(|(tag, _)| *tag == Some(key)) else {
+            return self.push(line, None);
+        };
+        let end = start + self.lines[start..].iter().take_while(|(tag, _)| *tag == Some(key)).count();
+        let text = line.into_text().unwrap_or_else(|_| Text::raw(line.to_owned()));
+        self.lines.splice(start..end, text.lines.into_iter().map(|line| (None, line)));
+    }
+
+    // false once the user wants out
+    async fn key(&mut self, key: KeyEvent, input: &mpsc::UnboundedSender<Input>) -> bool {
+        if key.kind == KeyEventKind::Release {
+            return true;
+        }
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        // scrolling the message pane, everything else edits the input line like the plain terminal
+        match key.code {
+            KeyCode::PageUp => return self.scroll_by(10),
+            KeyCode::PageDown => return self.scroll_by(-10),
+            KeyCode::Up if ctrl => return self.scroll_by(1),
+            KeyCode::Down if ctrl => return self.scroll_by(-1),
+            KeyCode::End if ctrl => return self.scroll_by(isize::MIN),
+            _ => {}
+        }
+        let Some(key) = Key::from_event(&key) else {
+            return true;
+        };
+
+        match self.editor.key(key).await {
+            Edit::Nothing | Edit::Moved => {}
+            Edit::Changed => {
+                let _ = input.send(Input::Draft(self.editor.text()));
+            }
+            Edit::Submit(line) => {
+                self.scroll = 0;
+                let _ = input.send(Input::Line(line));
+                let _ = input.send(Input::Draft(String::new()));
+            }
+            Edit::Candidates(candidates) => self.push(&candidates.join("  ").dimmed().to_string(), None),
+            Edit::Quit => return false,
+        }
+        true
+    }
+
+    // positive is further back, clamped to the top when drawing
+    fn scroll_by(&mut self, lines: isize) -> bool {
+        self.scroll = self.scroll.saturating_add_signed(lines);
+        true
+    }
+}
+
+async fn run(
+    terminal: Arc<SyncMutex<Option<DefaultTerminal>>>,
+    shared: Shared,
+    editor: LineEditor,
+    mut pane: mpsc::UnboundedReceiver<PaneLine>,
+    mut keys: mpsc::UnboundedReceiver<Event>,
+    input: mpsc::UnboundedSender<Input>,
+) {
+    let mut app = App { lines: Vec::new(), editor, scroll: 0 };
+    let mut redraw = time::interval(REDRAW_INTERVAL);
+
+    loop {
+        tokio::select! {
+            line = pane.recv() => match line {
+                Some(PaneLine::Line(line)) => app.push(&line, None),
+                Some(PaneLine::Pending(key, line)) => app.push(&line, Some(key)),
+                Some(PaneLine::Resolved(key, line)) => app.resolve(key, &line),
+                None => return,
+            },
+            ev = keys.recv() => match ev {
+                Some(Event::Key(key)) => {
+                    if !app.key(key, &input).await {
+                        return; // dropping input ends ChatClient::run
+                    }
+                }
+                Some(_) => {} // resizes just need the redraw below
+                None => return,
+            },
+            _ = redraw.tick() => {}
+        }
+
+        // copied out, the reader task takes these locks in its own order
+        let status = shared.status.lock().await.clone();
+        let chat = shared.client.session().await.map(|session| (session.chat_id, session.username));
+        let topic = shared.topic.lock().await.clone();
+
+        let Ok(mut terminal) = terminal.lock() else {
+            return;
+        };
+        let Some(terminal) = terminal.as_mut() else {
+            return; // already restored, we're on the way out
+        };
+        let _ = terminal.draw(|frame| draw(frame, &mut app, &status, chat.as_ref(), topic.as_deref()));
+    }
+}
+
+fn draw(frame: &mut Frame, app: &mut App, status: &Status, chat: Option<&(Uuid, String)>, topic: Option<&str>) {
+    let [main, status_area, input_area] = Layout::vertical([Constraint::Min(3), Constraint::Length(1), Constraint::Length(1)]).areas(frame.area());
+    let [messages_area, sidebar_area] = Layout::horizontal([Constraint::Min(10), Constraint::Length(SIDEBAR_WIDTH)]).areas(main);
+
+    // messages, pinned to the bottom unless scrolled back
+    let mut title = topic.map(|t| format!(" {} ", t)).unwrap_or_default();
+    let messages = Paragraph::new(Text::from(app.lines.iter().map(|(_, line)| line.clone()).collect::<Vec<_>>())).wrap(Wrap { trim: false });
+    let inner = Block::bordered().inner(messages_area);
+    let total = messages.line_count(inner.width);
+    let max_scroll = total.saturating_sub(inner.height as usize);
+    app.scroll = app.scroll.min(max_scroll);
+    if app.scroll > 0 {
+        title += &format!(" ↑{} (PgDn to go back) ", app.scroll);
+    }
+    let offset = (max_scroll - app.scroll).min(u16::MAX as usize) as u16;
+    frame.render_widget(messages.block(Block::bordered().title(title)).scroll((offset, 0)), messages_area);
+
+    // members, you in bold and a pencil next to whoever's typing
+    let me = chat.map(|(_, username)| username.as_str());
+    let members: Vec<Line> = status
+        .members
+        .iter()
+        .map(|name| {
+            let style = if Some(name.as_str()) == me { Style::default().add_modifier(Modifier::BOLD) } else { Style::default() };
+            let typing = if status.typing.contains(name) { " ✎" } else { "" };
+            Line::from(vec![Span::styled(name.clone(), style), Span::raw(typing)])
+        })
+        .collect();
+    frame.render_widget(Paragraph::new(members).block(Block::bordered().title(format!(" members ({}) ", status.members.len()))), sidebar_area);
+
+    // status bar
+    let connection = match status.reconnecting {
+        _ if status.connected => "● connected".green(),
+        Some((attempt, at)) => {
+            let wait = at.saturating_duration_since(time::Instant::now());
+            if wait.is_zero() { format!("○ reconnecting (attempt {})", attempt).yellow() } else { format!("○ reconnecting in {}s (attempt {})", wait.as_secs() + 1, attempt).yellow() }
+        }
+        None => "○ disconnected".red(),
+    };
+    let mut bar = format!(" {} to {}", connection, status.server);
+    match chat {
+        Some((chat_id, username)) => bar += &format!(" │ room {} │ {}", chat_id, username),
+        None => bar += " │ not in a room, /help for commands",
+    }
+    let typing: Vec<&str> = status.typing.iter().map(String::as_str).collect();
+    match typing.len() {
+        0 => {}
+        1 => bar += &format!(" │ {} is typing...", typing[0]),
+        _ => bar += &format!(" │ {} are typing...", typing.join(", ")),
+    }
+    let bar = bar.into_text().unwrap_or_else(|_| Text::raw(bar.clone()));
+    frame.render_widget(Paragraph::new(bar).style(Style::default().add_modifier(Modifier::REVERSED)), status_area);
+
+    // input, scrolled sideways so the cursor stays visible on a long line
+    let prompt = "> ";
+    let room = (input_area.width as usize).saturating_sub(prompt.len() + 1);
+    let (before, after) = app.editor.split();
+    let before: Vec<char> = before.chars().collect();
+    let before: String = before[before.len().saturating_sub(room)..].iter().collect();
+    let visible: String = before.chars().chain(after.chars()).take(room).collect();
+    let cursor_x = input_area.x + (prompt.len() + Line::raw(before.as_str()).width()) as u16;
+    frame.render_widget(Paragraph::new(format!("{}{}", prompt, visible)), input_area);
+    frame.set_cursor_position((cursor_x, input_area.y));
+}
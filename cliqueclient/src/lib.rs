@@ -0,0 +1,663 @@
+// the client side of the protocol as a library: connect, call a method per request and get
+// its response back, and read everything the server pushes from Events. the cli is built on
+// this, bots and tests can be too
+
+mod tls;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt, io,
+    sync::{Arc, Mutex as SyncMutex},
+    time::Duration,
+};
+
+use protocol::{
+    read_message, write_message, ChatExpiring, ChatMessage, CreateChatRequest, CreateChatResponse, CreateInviteRequest, CreateInviteResponse, DeleteMessageRequest,
+    DeleteMessageResponse, EditMessageRequest, EditMessageResponse, ErrorCode, ErrorResponse, FetchHistoryRequest, FetchHistoryResponse, FetchThreadRequest, FetchThreadResponse,
+    InviteCode, JoinChatRequest, JoinChatResponse, LeaveChatRequest, LeaveChatResponse, ListInvitesRequest, ListInvitesResponse, ListRoomsRequest, ListRoomsResponse,
+    MarkReadRequest, MarkReadResponse, MemberEvent, MessageDeleted, Packet, PasswordChanged, PinRequest, PinResponse, PinsUpdated, Ping, Pong, ProtocolMessage, ReactionRequest,
+    ReactionResponse, ReactionsUpdated, ReadPosition, RemovedFromChat, RevokeInviteRequest, RevokeInviteResponse, Role, RoomRef, SearchMessagesRequest, SearchMessagesResponse,
+    SendMessageRequest, SendMessageResponse, ServerNotice, SetRoleRequest, SetRoleResponse, SetRoomPasswordRequest, SetRoomPasswordResponse, SetTopicRequest, SetTopicResponse,
+    TopicChanged, Typing, TypingUpdated, HEARTBEAT_INTERVAL, HEARTBEAT_TIMEOUT,
+};
+use tokio::{
+    io::{split, AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::{mpsc, oneshot, Mutex},
+    task::AbortHandle,
+    time::{self, Instant},
+};
+use uuid::Uuid;
+
+use tls::Tls;
+pub use tls::TlsOptions;
+
+// reconnect attempts start this far apart and back off up to the max
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum ClientError {
+    Server(ErrorResponse), // the server turned the request down
+    NotInChat,             // the request needs a joined chat
+    Disconnected,
+    Unexpected(&'static str), // the server answered with the wrong kind of response
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Server(err) => write!(f, "{}: {}", err.code.as_str(), err.message),
+            ClientError::NotInChat => write!(f, "not in a chat"),
+            ClientError::Disconnected => write!(f, "connection to server lost"),
+            ClientError::Unexpected(kind) => write!(f, "unexpected {} from server", kind),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+// everything the server sends that isn't the answer to a request
+#[derive(Debug, Clone)]
+pub enum Event {
+    Message(ChatMessage),
+    MessageEdited(ChatMessage),
+    MessageDeleted(MessageDeleted),
+    ReactionsUpdated(ReactionsUpdated),
+    ReadPositionUpdated(ReadPosition),
+    TypingUpdated(TypingUpdated),
+    TopicChanged(TopicChanged),
+    PinsUpdated(PinsUpdated),
+    PasswordChanged(PasswordChanged),
+    MemberJoined(MemberEvent),
+    MemberLeft(MemberEvent),
+    ServerNotice(ServerNotice),
+    RemovedFromChat(RemovedFromChat),
+    ChatExpiring(ChatExpiring),
+    Disconnected(String), // what went wrong, reconnecting starts right after
+    Reconnecting { attempt: u32, delay: Duration },
+    // back and ready for requests. rejoined is None when there was no chat to get back into
+    Reconnected { rejoined: Option<Result<JoinChatResponse, ErrorResponse>> },
+}
+
+impl Event {
+    fn from_message(message: ProtocolMessage) -> Option<Self> {
+        Some(match message {
+            ProtocolMessage::MessageBroadcast(msg) => Event::Message(msg),
+            ProtocolMessage::MessageEdited(msg) => Event::MessageEdited(msg),
+            ProtocolMessage::MessageDeleted(deleted) => Event::MessageDeleted(deleted),
+            ProtocolMessage::ReactionsUpdated(update) => Event::ReactionsUpdated(update),
+            ProtocolMessage::ReadPositionUpdated(position) => Event::ReadPositionUpdated(position),
+            ProtocolMessage::TypingUpdated(update) => Event::TypingUpdated(update),
+            ProtocolMessage::TopicChanged(changed) => Event::TopicChanged(changed),
+            ProtocolMessage::PinsUpdated(update) => Event::PinsUpdated(update),
+            ProtocolMessage::PasswordChanged(changed) => Event::PasswordChanged(changed),
+            ProtocolMessage::MemberJoined(member) => Event::MemberJoined(member),
+            ProtocolMessage::MemberLeft(member) => Event::MemberLeft(member),
+            ProtocolMessage::ServerNotice(notice) => Event::ServerNotice(notice),
+            ProtocolMessage::RemovedFromChat(removed) => Event::RemovedFromChat(removed),
+            ProtocolMessage::ChatExpiring(expiring) => Event::ChatExpiring(expiring),
+            _ => return None,
+        })
+    }
+}
+
+pub struct Events(mpsc::UnboundedReceiver<Event>);
+
+impl Events {
+    pub async fn next(&mut self) -> Option<Event> {
+        self.0.recv().await
+    }
+}
+
+// the chat this connection is in, set by a successful join
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub chat_id: Uuid,
+    pub token: Uuid,
+    pub username: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Search {
+    pub query: Option<String>, // case-insensitive substring
+    pub author: Option<String>,
+    pub since: Option<u64>, // unix seconds, inclusive
+    pub until: Option<u64>, // unix seconds, exclusive
+    pub before_id: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+type Reply = Result<ProtocolMessage, ErrorResponse>;
+
+struct Outgoing {
+    message: ProtocolMessage,
+    reply: Option<oneshot::Sender<Reply>>, // None for messages nothing comes back for
+}
+
+// one tcp connection, a new one replaces it after a reconnect
+struct Connection {
+    // the server answers requests one at a time and in order, so each response belongs to
+    // the oldest request still waiting. None once the connection is gone
+    pending: SyncMutex<Option<VecDeque<oneshot::Sender<Reply>>>>,
+    last_seen: SyncMutex<Instant>,
+    closed: SyncMutex<Option<oneshot::Sender<String>>>, // tells the supervisor why it went away
+    tasks: SyncMutex<Vec<AbortHandle>>,                 // reader, writer and heartbeat
+}
+
+impl Connection {
+    // fails everything still waiting and stops the tasks so the old stream is dropped,
+    // only the first call does anything
+    fn close(&self, reason: String) {
+        let Ok(mut pending) = self.pending.lock() else {
+            return;
+        };
+        if pending.take().is_some() {
+            drop(pending);
+            if let Some(closed) = self.closed.lock().ok().and_then(|mut closed| closed.take()) {
+                let _ = closed.send(reason);
+            }
+            if let Ok(mut tasks) = self.tasks.lock() {
+                tasks.drain(..).for_each(|task| task.abort());
+            }
+        }
+    }
+
+    // a task that ends up here after close is stopped straight away
+    fn track(&self, task: AbortHandle) {
+        let Ok(mut tasks) = self.tasks.lock() else {
+            return task.abort();
+        };
+        match self.pending.lock() {
+            Ok(pending) if pending.is_some() => tasks.push(task),
+            _ => task.abort(),
+        }
+    }
+
+    // hands a response to the request that's been waiting longest
+    fn respond(&self, reply: Reply) {
+        let waiting = self.pending.lock().ok().and_then(|mut pending| pending.as_mut()?.pop_front());
+        if let Some(waiting) = waiting {
+            let _ = waiting.send(reply);
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Link {
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+}
+
+impl Link {
+    async fn request(&self, message: ProtocolMessage) -> Result<ProtocolMessage, ClientError> {
+        let (reply, response) = oneshot::channel();
+        self.outgoing.send(Outgoing { message, reply: Some(reply) }).map_err(|_| ClientError::Disconnected)?;
+        match response.await {
+            Ok(Ok(message)) => Ok(message),
+            Ok(Err(err)) => Err(ClientError::Server(err)),
+            Err(_) => Err(ClientError::Disconnected),
+        }
+    }
+}
+
+// what it takes to get back into the chat after a reconnect
+#[derive(Clone)]
+struct Rejoin {
+    chat_id: Uuid,
+    username: String,
+    password: Option<String>,
+    invite: Option<String>,
+    member_key: Option<Uuid>,
+}
+
+impl Rejoin {
+    fn request(&self) -> JoinChatRequest {
+        JoinChatRequest { chat_id: self.chat_id, username: self.username.clone(), password: self.password.clone(), invite: self.invite.clone(), member_key: self.member_key }
+    }
+}
+
+// plain tcp or tls on top of it
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+// outlives any one connection
+struct Shared {
+    addr: String,
+    tls: Option<Tls>,
+    link: SyncMutex<Option<Link>>, // None while reconnecting
+    session: Mutex<Option<Session>>,
+    rejoin: Mutex<Option<Rejoin>>,
+    member_keys: Mutex<HashMap<(Uuid, String), Uuid>>, // every name we've joined a chat as, see JoinChatResponse
+    events: mpsc::UnboundedSender<Event>,
+    tap: SyncMutex<Option<mpsc::UnboundedSender<ProtocolMessage>>>, // see Client::tap
+}
+
+#[derive(Clone)]
+pub struct Client {
+    shared: Arc<Shared>,
+}
+
+macro_rules! expect {
+    ($reply:expr, $kind:ident) => {
+        match $reply {
+            ProtocolMessage::$kind(resp) => Ok(resp),
+            other => Err(ClientError::Unexpected(other.kind())),
+        }
+    };
+}
+
+// if the connection drops the client keeps reconnecting in the background and gets back into
+// the chat it was in, Events says how that's going
+pub async fn connect(addr: &str) -> io::Result<(Client, Events)> {
+    connect_with(addr, None).await
+}
+
+// the same over tls, reconnects do the handshake again
+pub async fn connect_tls(addr: &str, options: TlsOptions) -> io::Result<(Client, Events)> {
+    connect_with(addr, Some(Tls::new(addr, options)?)).await
+}
+
+async fn connect_with(addr: &str, tls: Option<Tls>) -> io::Result<(Client, Events)> {
+    let (events_tx, events_rx) = mpsc::unbounded_channel();
+    let shared = Arc::new(Shared { addr: addr.to_owned(), tls, link: SyncMutex::new(None), session: Mutex::new(None), rejoin: Mutex::new(None), member_keys: Default::default(), events: events_tx, tap: SyncMutex::new(None) });
+
+    let stream = dial(&shared).await?;
+    let (link, closed) = open(&shared, stream);
+    if let Ok(mut current) = shared.link.lock() {
+        *current = Some(link);
+    }
+    tokio::spawn(supervise(shared.clone(), closed));
+
+    Ok((Client { shared }, Events(events_rx)))
+}
+
+// waits for the connection to drop, then reconnects with backoff and rejoins
+async fn supervise(shared: Arc<Shared>, mut closed: oneshot::Receiver<String>) {
+    loop {
+        let reason = (&mut closed).await.unwrap_or_else(|_| "Disconnected from server".into());
+        if let Ok(mut current) = shared.link.lock() {
+            *current = None;
+        }
+        let _ = shared.events.send(Event::Disconnected(reason));
+
+        let mut delay = RECONNECT_MIN_DELAY;
+        let mut attempt = 1;
+        let stream = loop {
+            let _ = shared.events.send(Event::Reconnecting { attempt, delay });
+            time::sleep(delay).await;
+            match dial(&shared).await {
+                Ok(stream) => break stream,
+                Err(_) => {
+                    delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                    attempt += 1;
+                }
+            }
+        };
+
+        let link;
+        (link, closed) = open(&shared, stream);
+        let Ok(rejoined) = rejoin(&shared, &link, delay).await else {
+            continue; // dropped again before we got back in, closed says why
+        };
+
+        // only handed out once it's back in the chat, so nothing goes out with the old token
+        if let Ok(mut current) = shared.link.lock() {
+            *current = Some(link);
+        }
+        let _ = shared.events.send(Event::Reconnected { rejoined });
+    }
+}
+
+// Err when the new connection went away too
+async fn rejoin(shared: &Shared, link: &Link, mut delay: Duration) -> Result<Option<Result<JoinChatResponse, ErrorResponse>>, ClientError> {
+    let Some(rejoin) = shared.rejoin.lock().await.clone() else {
+        return Ok(None);
+    };
+
+    let mut waited = Duration::ZERO;
+    loop {
+        let err = match link.request(ProtocolMessage::JoinChatRequest(rejoin.request())).await {
+            Ok(ProtocolMessage::JoinChatResponse(resp)) => return Ok(Some(Ok(resp))),
+            Ok(other) => ErrorResponse { code: ErrorCode::InternalError, message: format!("unexpected {}", other.kind()) },
+            Err(ClientError::Server(err)) => err,
+            Err(err) => return Err(err),
+        };
+
+        // the server may not have noticed the old connection is dead yet, it will within a heartbeat
+        if matches!(err.code, ErrorCode::UserAlreadyInRoom) && waited < HEARTBEAT_INTERVAL + HEARTBEAT_TIMEOUT {
+            time::sleep(delay).await;
+            waited += delay;
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+            continue;
+        }
+
+        // the room is gone or won't have us, there's no getting back in
+        *shared.session.lock().await = None;
+        *shared.rejoin.lock().await = None;
+        return Ok(Some(Err(err)));
+    }
+}
+
+async fn dial(shared: &Shared) -> io::Result<Box<dyn Stream>> {
+    let stream = TcpStream::connect(&shared.addr).await?;
+    match &shared.tls {
+        Some(tls) => Ok(Box::new(tls.wrap(stream).await?)),
+        None => Ok(Box::new(stream)),
+    }
+}
+
+// starts the tasks for one connection
+fn open(shared: &Arc<Shared>, stream: Box<dyn Stream>) -> (Link, oneshot::Receiver<String>) {
+    let (mut reader, mut writer) = split(stream);
+    let (outgoing, mut outgoing_rx) = mpsc::unbounded_channel::<Outgoing>();
+    let (closed_tx, closed) = oneshot::channel();
+    let conn = Arc::new(Connection {
+        pending: SyncMutex::new(Some(VecDeque::new())),
+        last_seen: SyncMutex::new(Instant::now()),
+        closed: SyncMutex::new(Some(closed_tx)),
+        tasks: SyncMutex::new(Vec::new()),
+    });
+
+    // writer task, a request is queued for its response before it goes out so the order matches
+    let writer_conn = conn.clone();
+    let writer = tokio::spawn(async move {
+        while let Some(Outgoing { message, reply }) = outgoing_rx.recv().await {
+            if let Some(reply) = reply {
+                match writer_conn.pending.lock() {
+                    Ok(mut pending) => match pending.as_mut() {
+                        Some(pending) => pending.push_back(reply),
+                        None => continue, // closed, dropping reply fails the request
+                    },
+                    Err(_) => break,
+                }
+            }
+            if let Err(e) = write_message(&mut writer, &Packet { version: 1, message }).await {
+                writer_conn.close(format!("Lost connection to server: {}", e));
+                break;
+            }
+        }
+    });
+
+    // heartbeat task that pings a quiet server and gives up on it if it never answers
+    let heartbeat_conn = conn.clone();
+    let heartbeat_chan = outgoing.clone();
+    let heartbeat = tokio::spawn(async move {
+        let mut ticker = time::interval(HEARTBEAT_INTERVAL);
+
+        loop {
+            ticker.tick().await;
+            let Ok(idle) = heartbeat_conn.last_seen.lock().map(|seen| seen.elapsed()) else {
+                break;
+            };
+            if idle >= HEARTBEAT_INTERVAL + HEARTBEAT_TIMEOUT {
+                heartbeat_conn.close(format!("Server stopped responding ({}s without a reply)", idle.as_secs()));
+                break;
+            }
+            if idle >= HEARTBEAT_INTERVAL && heartbeat_chan.send(Outgoing { message: ProtocolMessage::Ping(Ping {}), reply: None }).is_err() {
+                break;
+            }
+        }
+    });
+
+    // reader task, responses go to whoever is waiting on them and the rest become events
+    let reader_conn = conn.clone();
+    let reader_chan = outgoing.clone();
+    let reader_shared = shared.clone();
+    let reader = tokio::spawn(async move {
+        loop {
+            let message = match read_message(&mut reader).await {
+                Ok(Packet { message, .. }) => message,
+                Err(_) => {
+                    reader_conn.close("Disconnected from server".into());
+                    break;
+                }
+            };
+            if let Ok(mut seen) = reader_conn.last_seen.lock() {
+                *seen = Instant::now();
+            }
+            if !matches!(message, ProtocolMessage::Ping(_) | ProtocolMessage::Pong(_)) {
+                if let Some(tap) = reader_shared.tap.lock().ok().and_then(|tap| tap.clone()) {
+                    let _ = tap.send(message.clone());
+                }
+            }
+
+            match message {
+                ProtocolMessage::Ping(_) => {
+                    let _ = reader_chan.send(Outgoing { message: ProtocolMessage::Pong(Pong {}), reply: None });
+                }
+                ProtocolMessage::Pong(_) => {}
+                ProtocolMessage::ErrorResponse(err) => reader_conn.respond(Err(err)),
+                message if message.kind().ends_with("_response") => {
+                    // the session has to be there before anything that comes in after the join
+                    if let ProtocolMessage::JoinChatResponse(ref resp) = message {
+                        *reader_shared.session.lock().await = Some(Session { chat_id: resp.chat_id, token: resp.token, username: resp.username.clone(), role: resp.role });
+                    }
+                    reader_conn.respond(Ok(message));
+                }
+                message => {
+                    if let ProtocolMessage::RemovedFromChat(ref removed) = message {
+                        let mut session = reader_shared.session.lock().await;
+                        if session.as_ref().is_some_and(|s| s.chat_id == removed.chat_id) {
+                            *session = None;
+                            *reader_shared.rejoin.lock().await = None;
+                        }
+                    }
+                    if let Some(event) = Event::from_message(message) {
+                        let _ = reader_shared.events.send(event);
+                    }
+                }
+            }
+        }
+    });
+
+    for task in [writer, heartbeat, reader] {
+        conn.track(task.abort_handle());
+    }
+
+    (Link { outgoing }, closed)
+}
+
+impl Client {
+    // fails straight away while reconnecting, it's up to the caller whether to hold on to things
+    fn link(&self) -> Result<Link, ClientError> {
+        self.shared.link.lock().ok().and_then(|link| link.clone()).ok_or(ClientError::Disconnected)
+    }
+
+    async fn request(&self, message: ProtocolMessage) -> Result<ProtocolMessage, ClientError> {
+        self.link()?.request(message).await
+    }
+
+    // (chat_id, token) of the current chat
+    async fn ids(&self) -> Result<(Uuid, Uuid), ClientError> {
+        self.shared.session.lock().await.as_ref().map(|s| (s.chat_id, s.token)).ok_or(ClientError::NotInChat)
+    }
+
+    // kept while reconnecting, with the token from before until the rejoin goes through
+    pub async fn session(&self) -> Option<Session> {
+        self.shared.session.lock().await.clone()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.link().is_ok()
+    }
+
+    // a copy of everything the server sends from now on, responses included and before the
+    // request they answer returns. heartbeats are left out. only the last tap gets anything
+    pub fn tap(&self) -> mpsc::UnboundedReceiver<ProtocolMessage> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        if let Ok(mut tap) = self.shared.tap.lock() {
+            *tap = Some(tx);
+        }
+        rx
+    }
+
+    pub async fn create_chat(&self, password: Option<String>, ttl_secs: Option<u64>) -> Result<CreateChatResponse, ClientError> {
+        expect!(self.request(ProtocolMessage::CreateChatRequest(CreateChatRequest { password, ttl_secs })).await?, CreateChatResponse)
+    }
+
+    pub async fn join(&self, chat_id: Uuid, username: String, password: Option<String>) -> Result<JoinChatResponse, ClientError> {
+        self.join_with(Rejoin { chat_id, username, password, invite: None, member_key: None }).await
+    }
+
+    // invite codes say which chat they're for
+    pub async fn join_with_invite(&self, invite: String, username: String) -> Result<JoinChatResponse, ClientError> {
+        let Some(code) = InviteCode::decode(&invite) else {
+            return Err(ClientError::Server(ErrorResponse { code: ErrorCode::InvalidInvite, message: "Invalid invite".into() }));
+        };
+        self.join_with(Rejoin { chat_id: code.chat_id, username, password: None, invite: Some(invite), member_key: None }).await
+    }
+
+    // a key from an earlier process's JoinChatResponse, the next join under this name uses it
+    pub async fn remember_member_key(&self, chat_id: Uuid, username: String, key: Uuid) {
+        self.shared.member_keys.lock().await.insert((chat_id, username), key);
+    }
+
+    // remembered so a reconnect can get back in the same way, and as the same member as any
+    // earlier join under this name
+    async fn join_with(&self, mut rejoin: Rejoin) -> Result<JoinChatResponse, ClientError> {
+        let name = (rejoin.chat_id, rejoin.username.clone());
+        rejoin.member_key = self.shared.member_keys.lock().await.get(&name).copied();
+        let resp = expect!(self.request(ProtocolMessage::JoinChatRequest(rejoin.request())).await?, JoinChatResponse)?;
+        self.shared.member_keys.lock().await.insert(name, resp.member_key);
+        rejoin.member_key = Some(resp.member_key);
+        *self.shared.rejoin.lock().await = Some(rejoin);
+        Ok(resp)
+    }
+
+    pub async fn send(&self, message: String) -> Result<SendMessageResponse, ClientError> {
+        self.send_message(message, None).await
+    }
+
+    pub async fn reply(&self, reply_to: u64, message: String) -> Result<SendMessageResponse, ClientError> {
+        self.send_message(message, Some(reply_to)).await
+    }
+
+    async fn send_message(&self, message: String, reply_to: Option<u64>) -> Result<SendMessageResponse, ClientError> {
+        let (chat_id, token) = self.ids().await?;
+        let req = SendMessageRequest { chat_id, token, message, reply_to };
+        expect!(self.request(ProtocolMessage::SendMessageRequest(req)).await?, SendMessageResponse)
+    }
+
+    pub async fn edit(&self, message_id: u64, message: String) -> Result<EditMessageResponse, ClientError> {
+        let (chat_id, token) = self.ids().await?;
+        let req = EditMessageRequest { token, chat_id, message_id, message };
+        expect!(self.request(ProtocolMessage::EditMessageRequest(req)).await?, EditMessageResponse)
+    }
+
+    pub async fn delete(&self, message_id: u64) -> Result<DeleteMessageResponse, ClientError> {
+        let (chat_id, token) = self.ids().await?;
+        expect!(self.request(ProtocolMessage::DeleteMessageRequest(DeleteMessageRequest { token, chat_id, message_id })).await?, DeleteMessageResponse)
+    }
+
+    pub async fn react(&self, message_id: u64, emoji: String) -> Result<ReactionResponse, ClientError> {
+        let (chat_id, token) = self.ids().await?;
+        expect!(self.request(ProtocolMessage::AddReactionRequest(ReactionRequest { token, chat_id, message_id, emoji })).await?, AddReactionResponse)
+    }
+
+    pub async fn unreact(&self, message_id: u64, emoji: String) -> Result<ReactionResponse, ClientError> {
+        let (chat_id, token) = self.ids().await?;
+        expect!(self.request(ProtocolMessage::RemoveReactionRequest(ReactionRequest { token, chat_id, message_id, emoji })).await?, RemoveReactionResponse)
+    }
+
+    pub async fn fetch_thread(&self, message_id: u64) -> Result<FetchThreadResponse, ClientError> {
+        let (chat_id, token) = self.ids().await?;
+        expect!(self.request(ProtocolMessage::FetchThreadRequest(FetchThreadRequest { token, chat_id, message_id })).await?, FetchThreadResponse)
+    }
+
+    pub async fn fetch_history(&self, before_seq: Option<u64>, limit: Option<usize>) -> Result<FetchHistoryResponse, ClientError> {
+        let (chat_id, token) = self.ids().await?;
+        expect!(self.request(ProtocolMessage::FetchHistoryRequest(FetchHistoryRequest { token, chat_id, before_seq, limit })).await?, FetchHistoryResponse)
+    }
+
+    pub async fn search(&self, search: Search) -> Result<SearchMessagesResponse, ClientError> {
+        let (chat_id, token) = self.ids().await?;
+        let req = SearchMessagesRequest {
+            token,
+            chat_id,
+            query: search.query,
+            author: search.author,
+            since: search.since,
+            until: search.until,
+            before_id: search.before_id,
+            limit: search.limit,
+        };
+        expect!(self.request(ProtocolMessage::SearchMessagesRequest(req)).await?, SearchMessagesResponse)
+    }
+
+    pub async fn mark_read(&self, seq: u64) -> Result<MarkReadResponse, ClientError> {
+        let (chat_id, token) = self.ids().await?;
+        expect!(self.request(ProtocolMessage::MarkReadRequest(MarkReadRequest { token, chat_id, seq })).await?, MarkReadResponse)
+    }
+
+    // rooms don't need to be joined right now, each one's member key from its JoinChatResponse is checked instead
+    pub async fn list_rooms(&self, rooms: Vec<RoomRef>) -> Result<ListRoomsResponse, ClientError> {
+        expect!(self.request(ProtocolMessage::ListRoomsRequest(ListRoomsRequest { rooms })).await?, ListRoomsResponse)
+    }
+
+    pub async fn set_role(&self, username: String, role: Role) -> Result<SetRoleResponse, ClientError> {
+        let (chat_id, token) = self.ids().await?;
+        expect!(self.request(ProtocolMessage::SetRoleRequest(SetRoleRequest { token, chat_id, username, role })).await?, SetRoleResponse)
+    }
+
+    // None clears it
+    pub async fn set_topic(&self, topic: Option<String>) -> Result<SetTopicResponse, ClientError> {
+        let (chat_id, token) = self.ids().await?;
+        expect!(self.request(ProtocolMessage::SetTopicRequest(SetTopicRequest { token, chat_id, topic })).await?, SetTopicResponse)
+    }
+
+    pub async fn pin(&self, message_id: u64) -> Result<PinResponse, ClientError> {
+        let (chat_id, token) = self.ids().await?;
+        expect!(self.request(ProtocolMessage::PinMessageRequest(PinRequest { token, chat_id, message_id })).await?, PinMessageResponse)
+    }
+
+    pub async fn unpin(&self, message_id: u64) -> Result<PinResponse, ClientError> {
+        let (chat_id, token) = self.ids().await?;
+        expect!(self.request(ProtocolMessage::UnpinMessageRequest(PinRequest { token, chat_id, message_id })).await?, UnpinMessageResponse)
+    }
+
+    // None removes the password
+    pub async fn set_password(&self, password: Option<String>, kick_old: bool) -> Result<SetRoomPasswordResponse, ClientError> {
+        let (chat_id, token) = self.ids().await?;
+        let req = SetRoomPasswordRequest { token, chat_id, password: password.clone(), kick_old };
+        let resp = expect!(self.request(ProtocolMessage::SetRoomPasswordRequest(req)).await?, SetRoomPasswordResponse)?;
+        // so a reconnect uses the new one
+        if let Some(rejoin) = self.shared.rejoin.lock().await.as_mut() {
+            rejoin.password = password;
+        }
+        Ok(resp)
+    }
+
+    pub async fn create_invite(&self, expires_in_secs: Option<u64>, max_uses: Option<u32>) -> Result<CreateInviteResponse, ClientError> {
+        let (chat_id, token) = self.ids().await?;
+        let req = CreateInviteRequest { token, chat_id, expires_in_secs, max_uses };
+        expect!(self.request(ProtocolMessage::CreateInviteRequest(req)).await?, CreateInviteResponse)
+    }
+
+    pub async fn list_invites(&self) -> Result<ListInvitesResponse, ClientError> {
+        let (chat_id, token) = self.ids().await?;
+        expect!(self.request(ProtocolMessage::ListInvitesRequest(ListInvitesRequest { token, chat_id })).await?, ListInvitesResponse)
+    }
+
+    pub async fn revoke_invite(&self, invite_id: u64) -> Result<RevokeInviteResponse, ClientError> {
+        let (chat_id, token) = self.ids().await?;
+        expect!(self.request(ProtocolMessage::RevokeInviteRequest(RevokeInviteRequest { token, chat_id, invite_id })).await?, RevokeInviteResponse)
+    }
+
+    // nothing comes back for this one
+    pub async fn typing(&self, active: bool) -> Result<(), ClientError> {
+        let (chat_id, _) = self.ids().await?;
+        self.link()?.outgoing.send(Outgoing { message: ProtocolMessage::Typing(Typing { chat_id, active }), reply: None }).map_err(|_| ClientError::Disconnected)
+    }
+
+    // the session is gone as soon as this is called, whatever the server says
+    pub async fn leave(&self) -> Result<LeaveChatResponse, ClientError> {
+        let Some(session) = self.shared.session.lock().await.take() else {
+            return Err(ClientError::NotInChat);
+        };
+        *self.shared.rejoin.lock().await = None;
+        let req = LeaveChatRequest { chat_id: session.chat_id, token: session.token };
+        expect!(self.request(ProtocolMessage::LeaveChatRequest(req)).await?, LeaveChatResponse)
+    }
+}
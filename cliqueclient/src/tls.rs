@@ -0,0 +1,51 @@
+// tls.rs
+// the server itself speaks plain tcp, this is for reaching one behind a tls-terminating proxy
+
+use std::{io, path::PathBuf, sync::Arc};
+
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, ServerName},
+        ClientConfig, RootCertStore,
+    },
+    TlsConnector,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    pub server_name: Option<String>, // checked against the certificate, defaults to the host
+    pub ca_file: Option<PathBuf>,    // PEM certificates to trust instead of the usual public roots
+}
+
+pub(crate) struct Tls {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl Tls {
+    pub(crate) fn new(addr: &str, options: TlsOptions) -> io::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        match options.ca_file {
+            Some(path) => {
+                let certs = CertificateDer::pem_file_iter(&path).and_then(|certs| certs.collect::<Result<Vec<_>, _>>());
+                let certs = certs.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("can't read {}: {}", path.display(), e)))?;
+                let (added, _) = roots.add_parsable_certificates(certs);
+                if added == 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("no usable certificates in {}", path.display())));
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+
+        // "host:port" or "[v6]:port"
+        let host = options.server_name.unwrap_or_else(|| addr.rsplit_once(':').map_or(addr, |(host, _)| host).trim_matches(['[', ']']).to_owned());
+        let server_name = ServerName::try_from(host).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        Ok(Tls { connector: TlsConnector::from(Arc::new(config)), server_name })
+    }
+
+    pub(crate) async fn wrap(&self, stream: tokio::net::TcpStream) -> io::Result<tokio_rustls::client::TlsStream<tokio::net::TcpStream>> {
+        self.connector.connect(self.server_name.clone(), stream).await
+    }
+}
@@ -1,17 +1,27 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use serde::{Deserialize, Serialize};
-use serde_json;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use uuid::Uuid;
 
 pub type DynError = Box<dyn std::error::Error + Send + Sync>;
 
-#[derive(Serialize, Deserialize, Debug)]
+// a side pings the other after this long without hearing anything from it
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+// and gives up on the connection if there's still silence after this much longer
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+// the server drops a typing indicator that hasn't been refreshed for this long
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+// longest lifetime a temporary room can be created with
+pub const MAX_ROOM_TTL: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ErrorResponse {
     pub code: ErrorCode,
     pub message: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum ErrorCode {
     WrongPassword,
@@ -22,6 +32,32 @@ pub enum ErrorCode {
     InternalError,
     UserAlreadyInRoom,
     UserAlreadyInAnotherRoom,
+    ConnectionNotFound,
+    MessageNotFound,
+    Forbidden,
+    InvalidInvite, // forged, revoked, or for another room
+    InviteExpired, // past its expiry or out of uses
+}
+
+impl ErrorCode {
+    // same spelling as on the wire
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::WrongPassword => "wrong_password",
+            ErrorCode::PasswordMissing => "password_missing",
+            ErrorCode::ChatNotFound => "chat_not_found",
+            ErrorCode::InvalidFormat => "invalid_format",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::InternalError => "internal_error",
+            ErrorCode::UserAlreadyInRoom => "user_already_in_room",
+            ErrorCode::UserAlreadyInAnotherRoom => "user_already_in_another_room",
+            ErrorCode::ConnectionNotFound => "connection_not_found",
+            ErrorCode::MessageNotFound => "message_not_found",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::InvalidInvite => "invalid_invite",
+            ErrorCode::InviteExpired => "invite_expired",
+        }
+    }
 }
 
 // Header shared by all
@@ -44,7 +80,7 @@ pub struct Packet {
     pub message: ProtocolMessage,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type", rename_all = "snake_case", content = "body")]
 pub enum ProtocolMessage {
     CreateChatRequest(CreateChatRequest),
@@ -55,63 +91,610 @@ pub enum ProtocolMessage {
     SendMessageResponse(SendMessageResponse),
     LeaveChatRequest(LeaveChatRequest),
     LeaveChatResponse(LeaveChatResponse),
+    EditMessageRequest(EditMessageRequest),
+    EditMessageResponse(EditMessageResponse),
+    DeleteMessageRequest(DeleteMessageRequest),
+    DeleteMessageResponse(DeleteMessageResponse),
+    SetRoleRequest(SetRoleRequest),
+    SetRoleResponse(SetRoleResponse),
+    FetchThreadRequest(FetchThreadRequest),
+    FetchThreadResponse(FetchThreadResponse),
+    AddReactionRequest(ReactionRequest),
+    AddReactionResponse(ReactionResponse),
+    RemoveReactionRequest(ReactionRequest),
+    RemoveReactionResponse(ReactionResponse),
+    SetTopicRequest(SetTopicRequest),
+    SetTopicResponse(SetTopicResponse),
+    SetRoomPasswordRequest(SetRoomPasswordRequest),
+    SetRoomPasswordResponse(SetRoomPasswordResponse),
+    CreateInviteRequest(CreateInviteRequest),
+    CreateInviteResponse(CreateInviteResponse),
+    ListInvitesRequest(ListInvitesRequest),
+    ListInvitesResponse(ListInvitesResponse),
+    RevokeInviteRequest(RevokeInviteRequest),
+    RevokeInviteResponse(RevokeInviteResponse),
+    PinMessageRequest(PinRequest),
+    PinMessageResponse(PinResponse),
+    UnpinMessageRequest(PinRequest),
+    UnpinMessageResponse(PinResponse),
+    SearchMessagesRequest(SearchMessagesRequest),
+    SearchMessagesResponse(SearchMessagesResponse),
+    FetchHistoryRequest(FetchHistoryRequest),
+    FetchHistoryResponse(FetchHistoryResponse),
+    MarkReadRequest(MarkReadRequest),
+    MarkReadResponse(MarkReadResponse),
+    ListRoomsRequest(ListRoomsRequest),
+    ListRoomsResponse(ListRoomsResponse),
     ErrorResponse(ErrorResponse),
 
+    // keepalive, either side can ping and the other answers with pong
+    Ping(Ping),
+    Pong(Pong),
+
+    // ephemeral, nothing is sent back and it's never stored
+    Typing(Typing),
+
     // across async handlers to broadcast messages
     MessageBroadcast(ChatMessage),
+    MessageEdited(ChatMessage),
+    MessageDeleted(MessageDeleted),
+    ReactionsUpdated(ReactionsUpdated),
+    ReadPositionUpdated(ReadPosition),
+    TypingUpdated(TypingUpdated),
+    TopicChanged(TopicChanged),
+    PinsUpdated(PinsUpdated),
+    PasswordChanged(PasswordChanged),
+    MemberJoined(MemberEvent),
+    MemberLeft(MemberEvent),
+
+    // pushed by the server without a request
+    ServerNotice(ServerNotice),
+    RemovedFromChat(RemovedFromChat),
+    ChatExpiring(ChatExpiring),
+
+    // only accepted on the server's admin socket
+    AdminRequest(AdminCommand),
+    AdminResponse(AdminResponse),
+}
+
+impl ProtocolMessage {
+    // the "type" tag this message is sent with
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProtocolMessage::CreateChatRequest(_) => "create_chat_request",
+            ProtocolMessage::CreateChatResponse(_) => "create_chat_response",
+            ProtocolMessage::JoinChatRequest(_) => "join_chat_request",
+            ProtocolMessage::JoinChatResponse(_) => "join_chat_response",
+            ProtocolMessage::SendMessageRequest(_) => "send_message_request",
+            ProtocolMessage::SendMessageResponse(_) => "send_message_response",
+            ProtocolMessage::LeaveChatRequest(_) => "leave_chat_request",
+            ProtocolMessage::LeaveChatResponse(_) => "leave_chat_response",
+            ProtocolMessage::EditMessageRequest(_) => "edit_message_request",
+            ProtocolMessage::EditMessageResponse(_) => "edit_message_response",
+            ProtocolMessage::DeleteMessageRequest(_) => "delete_message_request",
+            ProtocolMessage::DeleteMessageResponse(_) => "delete_message_response",
+            ProtocolMessage::SetRoleRequest(_) => "set_role_request",
+            ProtocolMessage::SetRoleResponse(_) => "set_role_response",
+            ProtocolMessage::FetchThreadRequest(_) => "fetch_thread_request",
+            ProtocolMessage::FetchThreadResponse(_) => "fetch_thread_response",
+            ProtocolMessage::AddReactionRequest(_) => "add_reaction_request",
+            ProtocolMessage::AddReactionResponse(_) => "add_reaction_response",
+            ProtocolMessage::RemoveReactionRequest(_) => "remove_reaction_request",
+            ProtocolMessage::RemoveReactionResponse(_) => "remove_reaction_response",
+            ProtocolMessage::SetTopicRequest(_) => "set_topic_request",
+            ProtocolMessage::SetTopicResponse(_) => "set_topic_response",
+            ProtocolMessage::SetRoomPasswordRequest(_) => "set_room_password_request",
+            ProtocolMessage::SetRoomPasswordResponse(_) => "set_room_password_response",
+            ProtocolMessage::CreateInviteRequest(_) => "create_invite_request",
+            ProtocolMessage::CreateInviteResponse(_) => "create_invite_response",
+            ProtocolMessage::ListInvitesRequest(_) => "list_invites_request",
+            ProtocolMessage::ListInvitesResponse(_) => "list_invites_response",
+            ProtocolMessage::RevokeInviteRequest(_) => "revoke_invite_request",
+            ProtocolMessage::RevokeInviteResponse(_) => "revoke_invite_response",
+            ProtocolMessage::PinMessageRequest(_) => "pin_message_request",
+            ProtocolMessage::PinMessageResponse(_) => "pin_message_response",
+            ProtocolMessage::UnpinMessageRequest(_) => "unpin_message_request",
+            ProtocolMessage::UnpinMessageResponse(_) => "unpin_message_response",
+            ProtocolMessage::SearchMessagesRequest(_) => "search_messages_request",
+            ProtocolMessage::SearchMessagesResponse(_) => "search_messages_response",
+            ProtocolMessage::FetchHistoryRequest(_) => "fetch_history_request",
+            ProtocolMessage::FetchHistoryResponse(_) => "fetch_history_response",
+            ProtocolMessage::MarkReadRequest(_) => "mark_read_request",
+            ProtocolMessage::MarkReadResponse(_) => "mark_read_response",
+            ProtocolMessage::ListRoomsRequest(_) => "list_rooms_request",
+            ProtocolMessage::ListRoomsResponse(_) => "list_rooms_response",
+            ProtocolMessage::ErrorResponse(_) => "error_response",
+            ProtocolMessage::Ping(_) => "ping",
+            ProtocolMessage::Pong(_) => "pong",
+            ProtocolMessage::Typing(_) => "typing",
+            ProtocolMessage::MessageBroadcast(_) => "message_broadcast",
+            ProtocolMessage::MessageEdited(_) => "message_edited",
+            ProtocolMessage::MessageDeleted(_) => "message_deleted",
+            ProtocolMessage::ReactionsUpdated(_) => "reactions_updated",
+            ProtocolMessage::ReadPositionUpdated(_) => "read_position_updated",
+            ProtocolMessage::TypingUpdated(_) => "typing_updated",
+            ProtocolMessage::TopicChanged(_) => "topic_changed",
+            ProtocolMessage::PinsUpdated(_) => "pins_updated",
+            ProtocolMessage::PasswordChanged(_) => "password_changed",
+            ProtocolMessage::MemberJoined(_) => "member_joined",
+            ProtocolMessage::MemberLeft(_) => "member_left",
+            ProtocolMessage::ServerNotice(_) => "server_notice",
+            ProtocolMessage::RemovedFromChat(_) => "removed_from_chat",
+            ProtocolMessage::ChatExpiring(_) => "chat_expiring",
+            ProtocolMessage::AdminRequest(_) => "admin_request",
+            ProtocolMessage::AdminResponse(_) => "admin_response",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChatMessage {
+    pub id: u64, // assigned by the server, counts up from 1 within a room
+    pub sent_at: u64, // unix seconds, server clock
     pub username: String,
     pub message: String,
+    pub edited: bool,
+    pub reply_to: Option<u64>, // parent message when this is a threaded reply
+    #[serde(default)]
+    pub reactions: Vec<ReactionCount>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Owner, // whoever created the room, once they join it
+    Moderator,
+    Member,
 }
 
 /* These are the actual bodies */
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CreateChatRequest {
     pub password: Option<String>,
+    pub ttl_secs: Option<u64>, // ephemeral room, deleted this long after creation
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CreateChatResponse {
     pub chat_id: Uuid,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct JoinChatRequest {
     pub chat_id: Uuid,
     pub username: String,
     pub password: Option<String>,
+    #[serde(default)]
+    pub invite: Option<String>, // an invite code lets you in without the password
+    #[serde(default)]
+    pub member_key: Option<Uuid>, // from an earlier JoinChatResponse, to come back as the same member
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct JoinChatResponse {
     pub chat_id: Uuid,
     pub token: Uuid,
     pub username: String,
+    pub role: Role,
+    pub history: Vec<ChatMessage>, // the most recent messages, oldest first
+    pub next_before_seq: Option<u64>, // cursor for FetchHistoryRequest when there's more before that
+    pub last_read_seq: Option<u64>,   // where this username left off, None if it's never been here
+    pub unread: usize,
+    pub read_positions: Vec<ReadPosition>, // everyone's, for read receipts
+    #[serde(default)]
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub pins: Vec<ChatMessage>, // in the order they were pinned
+    #[serde(default)]
+    pub members: Vec<String>, // including you, kept current with MemberJoined/MemberLeft
+    #[serde(default)]
+    pub member_key: Uuid, // proves this name is yours when joining again, see JoinChatRequest
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemberEvent {
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SendMessageRequest {
     pub token: Uuid,
     pub chat_id: Uuid,
     pub message: String,
+    pub reply_to: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct SendMessageResponse {}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SendMessageResponse {
+    pub message_id: u64,
+}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LeaveChatRequest {
     pub token: Uuid,
     pub chat_id: Uuid,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LeaveChatResponse {}
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EditMessageRequest {
+    pub token: Uuid,
+    pub chat_id: Uuid,
+    pub message_id: u64,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EditMessageResponse {}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteMessageRequest {
+    pub token: Uuid,
+    pub chat_id: Uuid,
+    pub message_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteMessageResponse {}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageDeleted {
+    pub message_id: u64,
+    pub deleted_by: String,
+}
+
+// owner only, and only Moderator or Member can be handed out
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetRoleRequest {
+    pub token: Uuid,
+    pub chat_id: Uuid,
+    pub username: String,
+    pub role: Role,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetRoleResponse {}
+
+// a message and every reply below it, at any depth
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FetchThreadRequest {
+    pub token: Uuid,
+    pub chat_id: Uuid,
+    pub message_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FetchThreadResponse {
+    pub root: ChatMessage,
+    pub replies: Vec<ChatMessage>,
+}
+
+// same body for adding and removing
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReactionRequest {
+    pub token: Uuid,
+    pub chat_id: Uuid,
+    pub message_id: u64,
+    pub emoji: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReactionResponse {}
+
+// the full set of reactions on a message after someone changed them
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReactionsUpdated {
+    pub message_id: u64,
+    pub reactions: Vec<ReactionCount>,
+}
+
+// owner and moderators only, None clears it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetTopicRequest {
+    pub token: Uuid,
+    pub chat_id: Uuid,
+    pub topic: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetTopicResponse {}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TopicChanged {
+    pub topic: Option<String>,
+    pub changed_by: String,
+}
+
+// owner only, None removes the password. with kick_old everyone who got in with the
+// old password (rather than an invite, or before there was one) is removed from the room
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetRoomPasswordRequest {
+    pub token: Uuid,
+    pub chat_id: Uuid,
+    pub password: Option<String>,
+    pub kick_old: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetRoomPasswordResponse {
+    pub kicked: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PasswordChanged {
+    pub changed_by: String,
+    pub password_protected: bool,
+    pub kicked: Vec<String>,
+}
+
+// owner and moderators only. both limits are optional, an invite without them works until revoked
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreateInviteRequest {
+    pub token: Uuid,
+    pub chat_id: Uuid,
+    pub expires_in_secs: Option<u64>,
+    pub max_uses: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreateInviteResponse {
+    pub invite: InviteInfo,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListInvitesRequest {
+    pub token: Uuid,
+    pub chat_id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListInvitesResponse {
+    pub invites: Vec<InviteInfo>, // only ones that can still be used
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RevokeInviteRequest {
+    pub token: Uuid,
+    pub chat_id: Uuid,
+    pub invite_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RevokeInviteResponse {}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InviteInfo {
+    pub id: u64,
+    pub code: String,
+    pub created_by: String,
+    pub expires_at: Option<u64>, // unix seconds
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+}
+
+// invite codes are base64url(chat_id | invite id | mac). only the server can check the mac,
+// clients just need the chat_id out of it to know where they're joining
+pub const INVITE_MAC_LEN: usize = 16;
+
+pub struct InviteCode {
+    pub chat_id: Uuid,
+    pub invite_id: u64,
+    pub mac: [u8; INVITE_MAC_LEN],
+}
+
+impl InviteCode {
+    // the part the mac is computed over
+    pub fn payload(chat_id: Uuid, invite_id: u64) -> [u8; 24] {
+        let mut payload = [0u8; 24];
+        payload[..16].copy_from_slice(chat_id.as_bytes());
+        payload[16..].copy_from_slice(&invite_id.to_be_bytes());
+        payload
+    }
+
+    pub fn encode(&self) -> String {
+        let mut bytes = InviteCode::payload(self.chat_id, self.invite_id).to_vec();
+        bytes.extend_from_slice(&self.mac);
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    pub fn decode(code: &str) -> Option<InviteCode> {
+        let bytes = URL_SAFE_NO_PAD.decode(code.trim()).ok()?;
+        if bytes.len() != 24 + INVITE_MAC_LEN {
+            return None;
+        }
+        let chat_id = Uuid::from_slice(&bytes[..16]).ok()?;
+        let invite_id = u64::from_be_bytes(bytes[16..24].try_into().ok()?);
+        let mac = bytes[24..].try_into().ok()?;
+        Some(InviteCode { chat_id, invite_id, mac })
+    }
+}
+
+// owner and moderators only, same as the topic
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PinRequest {
+    pub token: Uuid,
+    pub chat_id: Uuid,
+    pub message_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PinResponse {}
+
+// the whole pin list after message_id was pinned or unpinned
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PinsUpdated {
+    pub message_id: u64,
+    pub pinned: bool,
+    pub changed_by: String,
+    pub pins: Vec<ChatMessage>,
+}
+
+// every filter is optional, results come newest first
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchMessagesRequest {
+    pub token: Uuid,
+    pub chat_id: Uuid,
+    pub query: Option<String>, // case-insensitive substring
+    pub author: Option<String>,
+    pub since: Option<u64>, // unix seconds, inclusive
+    pub until: Option<u64>, // unix seconds, exclusive
+    pub before_id: Option<u64>, // cursor, only look at messages older than this one
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchMessagesResponse {
+    pub results: Vec<ChatMessage>,
+    pub next_before_id: Option<u64>, // set when there are more results, pass it back as before_id
+}
+
+// scrollback, seq is a message id
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FetchHistoryRequest {
+    pub token: Uuid,
+    pub chat_id: Uuid,
+    pub before_seq: Option<u64>, // None starts from the newest message
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FetchHistoryResponse {
+    pub messages: Vec<ChatMessage>, // oldest first
+    pub next_before_seq: Option<u64>, // set when there's older history, pass it back as before_seq
+}
+
+// the member has seen everything up to and including seq
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReadPosition {
+    pub username: String,
+    pub seq: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarkReadRequest {
+    pub token: Uuid,
+    pub chat_id: Uuid,
+    pub seq: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarkReadResponse {}
+
+// status of rooms a client has been in, under the username it used there
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListRoomsRequest {
+    pub rooms: Vec<RoomRef>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomRef {
+    pub chat_id: Uuid,
+    pub username: String,
+    #[serde(default)]
+    pub member_key: Uuid, // from the JoinChatResponse, rooms it doesn't match are left out
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListRoomsResponse {
+    pub rooms: Vec<RoomListing>, // rooms that no longer exist are left out
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomListing {
+    pub chat_id: Uuid,
+    pub members: usize,
+    pub unread: usize,
+    pub last_message_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ping {}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Pong {}
+
+// the sender is resolved from the connection, it has to be in chat_id.
+// active has to be resent every so often (well within TYPING_TIMEOUT) to keep the indicator up
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Typing {
+    pub chat_id: Uuid,
+    pub active: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TypingUpdated {
+    pub username: String,
+    pub active: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerNotice {
+    pub message: String,
+}
+
+// the client is no longer in chat_id, e.g. because an operator deleted the room
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemovedFromChat {
+    pub chat_id: Uuid,
+    pub reason: String,
+}
+
+// heads up that the server is about to delete chat_id
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatExpiring {
+    pub chat_id: Uuid,
+    pub expires_in_secs: u64,
+    pub reason: String,
+}
+
+/* Admin bodies */
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminCommand {
+    ListRooms,
+    ListConnections,
+    DeleteRoom { chat_id: Uuid },
+    Disconnect { connection_id: u64 },
+    DisconnectUser { username: String },
+    Notice { message: String },
+    ReloadConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum AdminResponse {
+    Rooms { rooms: Vec<RoomSummary> },
+    Connections { connections: Vec<ConnectionSummary> },
+    Done { message: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomSummary {
+    pub chat_id: Uuid,
+    pub members: Vec<String>,
+    pub password_protected: bool,
+    pub message_count: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectionSummary {
+    pub connection_id: u64,
+    pub peer: String,
+    pub username: Option<String>,
+    pub chat_id: Option<Uuid>,
+    pub connected_secs: u64,
+}
+
 pub async fn read_message<R: AsyncReadExt + Unpin>(src: &mut R) -> Result<Packet, DynError> {
     let mut header_bytes = [0u8; 5];
     src.read_exact(&mut header_bytes).await?;
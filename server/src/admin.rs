@@ -0,0 +1,101 @@
+// admin control channel. it's a unix socket only the server's user can open (0600),
+// speaking the same framing as the chat protocol but only AdminRequest/AdminResponse.
+
+use std::{fs, io::ErrorKind, os::unix::fs::PermissionsExt, path::PathBuf, sync::Arc};
+
+use protocol::{read_message, write_message, AdminCommand, AdminResponse, DynError, ErrorCode, ErrorResponse, Packet, ProtocolMessage};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    sync::Mutex,
+};
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::server::ChatServer;
+
+pub async fn serve(path: PathBuf, state: Arc<Mutex<ChatServer>>) -> Result<(), DynError> {
+    // a socket file left over from a previous run would make bind fail
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    let listener = UnixListener::bind(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    info!(path = %path.display(), "admin socket listening");
+
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let copy = Arc::clone(&state);
+
+        tokio::spawn(
+            async move {
+                if let Err(e) = handle_admin(socket, copy).await {
+                    warn!(error = %e, "admin connection closed with an error");
+                }
+            }
+            .instrument(info_span!("admin")),
+        );
+    }
+}
+
+async fn handle_admin(mut socket: UnixStream, state: Arc<Mutex<ChatServer>>) -> Result<(), DynError> {
+    loop {
+        let packet = match read_message(&mut socket).await {
+            Ok(pkt) => pkt,
+            Err(_) => return Ok(()),
+        };
+
+        let ProtocolMessage::AdminRequest(cmd) = packet.message else {
+            reply(&mut socket, error(ErrorCode::InvalidFormat, "Only admin requests are accepted here")).await?;
+            continue;
+        };
+
+        info!(?cmd, "admin command");
+        let mut server = state.lock().await;
+        let response = match cmd {
+            AdminCommand::ListRooms => ProtocolMessage::AdminResponse(AdminResponse::Rooms { rooms: server.room_summaries() }),
+            AdminCommand::ListConnections => ProtocolMessage::AdminResponse(AdminResponse::Connections { connections: server.connection_summaries() }),
+            AdminCommand::DeleteRoom { chat_id } => {
+                if server.delete_room(chat_id, "Room was deleted by an operator") {
+                    done(format!("deleted room {chat_id}"))
+                } else {
+                    error(ErrorCode::ChatNotFound, "Chat not found")
+                }
+            }
+            AdminCommand::Disconnect { connection_id } => {
+                if server.disconnect(connection_id, "Disconnected by an operator") {
+                    done(format!("disconnected connection {connection_id}"))
+                } else {
+                    error(ErrorCode::ConnectionNotFound, "Connection not found")
+                }
+            }
+            AdminCommand::DisconnectUser { username } => match server.disconnect_user(&username, "Disconnected by an operator") {
+                0 => error(ErrorCode::ConnectionNotFound, "No connection with that username"),
+                n => done(format!("disconnected {n} connection(s) as {username}")),
+            },
+            AdminCommand::Notice { message } => {
+                let n = server.notice_all(&message);
+                done(format!("notice sent to {n} connection(s)"))
+            }
+            AdminCommand::ReloadConfig => match server.reload_config() {
+                Ok(summary) => done(summary),
+                Err(e) => error(ErrorCode::InternalError, &format!("Reload failed: {e}")),
+            },
+        };
+        drop(server);
+
+        reply(&mut socket, response).await?;
+    }
+}
+
+fn done(message: String) -> ProtocolMessage {
+    ProtocolMessage::AdminResponse(AdminResponse::Done { message })
+}
+
+fn error(code: ErrorCode, message: &str) -> ProtocolMessage {
+    ProtocolMessage::ErrorResponse(ErrorResponse { code, message: message.into() })
+}
+
+async fn reply(socket: &mut UnixStream, message: ProtocolMessage) -> Result<(), DynError> {
+    write_message(socket, &Packet { version: 1, message }).await
+}
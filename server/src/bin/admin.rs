@@ -0,0 +1,93 @@
+// talks to a running server over its ADMIN_SOCKET
+// usage: admin [--socket <path>] <command> [args]
+
+use std::{env, path::PathBuf};
+
+use protocol::{read_message, write_message, AdminCommand, AdminResponse, DynError, Packet, ProtocolMessage};
+use tokio::net::UnixStream;
+use uuid::Uuid;
+
+const USAGE: &str = r#"Usage: admin [--socket <path>] <command>
+
+Commands:
+rooms                      — list rooms and their members
+connections                — list open connections
+delete-room <chat_id>      — delete a room, members are removed from it
+disconnect <connection_id> — close one connection
+disconnect-user <username> — close every connection using that username
+notice <message>           — send a notice to every connection
+reload                     — reload the server config (.env and environment)
+
+The socket defaults to $ADMIN_SOCKET, which is also read from .env.
+"#;
+
+#[tokio::main]
+async fn main() -> Result<(), DynError> {
+    let _ = dotenvy::dotenv();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    let mut socket_path = env::var_os("ADMIN_SOCKET").map(PathBuf::from);
+    if args.first().map(String::as_str) == Some("--socket") {
+        if args.len() < 2 {
+            return Err(USAGE.into());
+        }
+        socket_path = Some(PathBuf::from(args.remove(1)));
+        args.remove(0);
+    }
+
+    let Some(socket_path) = socket_path else {
+        return Err(format!("no admin socket given, set ADMIN_SOCKET or pass --socket\n\n{USAGE}").into());
+    };
+    let cmd = parse_command(&args).ok_or(USAGE)?;
+
+    let mut socket = UnixStream::connect(&socket_path).await.map_err(|e| format!("could not connect to {}: {e}", socket_path.display()))?;
+    write_message(&mut socket, &Packet { version: 1, message: ProtocolMessage::AdminRequest(cmd) }).await?;
+
+    match read_message(&mut socket).await?.message {
+        ProtocolMessage::AdminResponse(resp) => print_response(resp),
+        ProtocolMessage::ErrorResponse(err) => return Err(format!("{:?}: {}", err.code, err.message).into()),
+        other => return Err(format!("unexpected response: {other:?}").into()),
+    }
+    Ok(())
+}
+
+fn parse_command(args: &[String]) -> Option<AdminCommand> {
+    let (name, rest) = args.split_first()?;
+    let cmd = match (name.as_str(), rest) {
+        ("rooms", []) => AdminCommand::ListRooms,
+        ("connections", []) => AdminCommand::ListConnections,
+        ("delete-room", [chat_id]) => AdminCommand::DeleteRoom { chat_id: Uuid::parse_str(chat_id).ok()? },
+        ("disconnect", [id]) => AdminCommand::Disconnect { connection_id: id.parse().ok()? },
+        ("disconnect-user", [username]) => AdminCommand::DisconnectUser { username: username.clone() },
+        ("notice", words) if !words.is_empty() => AdminCommand::Notice { message: words.join(" ") },
+        ("reload", []) => AdminCommand::ReloadConfig,
+        _ => return None,
+    };
+    Some(cmd)
+}
+
+fn print_response(resp: AdminResponse) {
+    match resp {
+        AdminResponse::Rooms { rooms } => {
+            if rooms.is_empty() {
+                println!("no rooms");
+            }
+            for room in rooms {
+                let lock = if room.password_protected { " (password)" } else { "" };
+                let members = if room.members.is_empty() { String::new() } else { format!(": {}", room.members.join(", ")) };
+                println!("{}{} — {} message(s), {} member(s){}", room.chat_id, lock, room.message_count, room.members.len(), members);
+            }
+        }
+        AdminResponse::Connections { connections } => {
+            if connections.is_empty() {
+                println!("no connections");
+            }
+            for conn in connections {
+                let username = conn.username.as_deref().unwrap_or("-");
+                let chat_id = conn.chat_id.map(|id| id.to_string()).unwrap_or_else(|| "-".into());
+                println!("#{} {} user={} chat={} up {}s", conn.connection_id, conn.peer, username, chat_id, conn.connected_secs);
+            }
+        }
+        AdminResponse::Done { message } => println!("{message}"),
+    }
+}
@@ -0,0 +1,77 @@
+use std::{env, path::PathBuf, str::FromStr, time::Duration};
+
+use protocol::DynError;
+
+// ten years, far enough out for any room policy
+const MAX_DURATION: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+// everything comes from env vars, a .env file in the working dir is loaded first if there is one
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub port: u16,
+    pub log_level: String, // anything EnvFilter understands, e.g. "debug" or "server=trace,info"
+    pub log_format: LogFormat,
+    pub metrics_port: Option<u16>, // prometheus endpoint is off unless this is set
+    pub admin_socket: Option<PathBuf>, // same for the admin unix socket
+    pub empty_room_ttl: Option<Duration>, // delete rooms nobody has been in for this long
+    pub idle_room_ttl: Option<Duration>,  // delete rooms nobody has joined or written to for this long
+    pub expiry_warning: Duration,         // how far ahead members are told their room is going away
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Result<Self, DynError> {
+        let _ = dotenvy::dotenv();
+        Self::read()
+    }
+
+    // on reload the .env file wins over whatever was loaded at startup, so edits to it take effect
+    pub fn reload() -> Result<Self, DynError> {
+        let _ = dotenvy::dotenv_override();
+        Self::read()
+    }
+
+    fn read() -> Result<Self, DynError> {
+        let port = parse_var("PORT")?.unwrap_or(8080);
+
+        let log_level = env::var("LOG_LEVEL").unwrap_or_else(|_| "info".into());
+
+        let log_format = match env::var("LOG_FORMAT").as_deref() {
+            Ok("json") => LogFormat::Json,
+            Ok("pretty") | Err(_) => LogFormat::Pretty,
+            Ok(other) => return Err(format!("invalid LOG_FORMAT: {other} (expected pretty or json)").into()),
+        };
+
+        let metrics_port = parse_var("METRICS_PORT")?;
+        let admin_socket = env::var_os("ADMIN_SOCKET").map(PathBuf::from);
+
+        let empty_room_ttl = parse_duration("EMPTY_ROOM_TTL_MINUTES", 60)?;
+        let idle_room_ttl = parse_duration("IDLE_ROOM_TTL_DAYS", 24 * 60 * 60)?;
+        let expiry_warning = parse_duration("ROOM_EXPIRY_WARNING_MINUTES", 60)?.unwrap_or(Duration::from_secs(5 * 60));
+
+        Ok(ServerConfig { port, log_level, log_format, metrics_port, admin_socket, empty_room_ttl, idle_room_ttl, expiry_warning })
+    }
+}
+
+// a count of unit_secs, anything past MAX_DURATION is refused rather than left to overflow later
+fn parse_duration(name: &str, unit_secs: u64) -> Result<Option<Duration>, DynError> {
+    let Some(amount) = parse_var::<u64>(name)? else {
+        return Ok(None);
+    };
+    match amount.checked_mul(unit_secs).map(Duration::from_secs) {
+        Some(duration) if duration <= MAX_DURATION => Ok(Some(duration)),
+        _ => Err(format!("invalid {name}: {amount} (at most {} days)", MAX_DURATION.as_secs() / (24 * 60 * 60)).into()),
+    }
+}
+
+fn parse_var<T: FromStr>(name: &str) -> Result<Option<T>, DynError> {
+    match env::var(name) {
+        Ok(value) => value.parse().map(Some).map_err(|_| format!("invalid {name}: {value}").into()),
+        Err(_) => Ok(None),
+    }
+}
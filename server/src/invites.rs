@@ -0,0 +1,100 @@
+// signs and checks invite codes. the key is made fresh at startup, rooms don't outlive the
+// process either so there's nothing an older code could still be good for
+
+use hmac::{Hmac, Mac};
+use protocol::{DynError, InviteCode, INVITE_MAC_LEN};
+use rand::{rngs::OsRng, TryRngCore};
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone)]
+pub struct InviteKey([u8; 32]);
+
+impl InviteKey {
+    pub fn generate() -> Result<Self, DynError> {
+        let mut key = [0u8; 32];
+        OsRng.try_fill_bytes(&mut key)?;
+        Ok(InviteKey(key))
+    }
+
+    fn mac(&self, chat_id: Uuid, invite_id: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.0).expect("hmac takes keys of any length");
+        mac.update(&InviteCode::payload(chat_id, invite_id));
+        mac
+    }
+
+    pub fn sign(&self, chat_id: Uuid, invite_id: u64) -> String {
+        let tag = self.mac(chat_id, invite_id).finalize().into_bytes();
+        let mut mac = [0u8; INVITE_MAC_LEN];
+        mac.copy_from_slice(&tag[..INVITE_MAC_LEN]);
+        InviteCode { chat_id, invite_id, mac }.encode()
+    }
+
+    // None for anything this server didn't hand out
+    pub fn verify(&self, code: &str) -> Option<InviteCode> {
+        let invite = InviteCode::decode(code)?;
+        self.mac(invite.chat_id, invite.invite_id).verify_truncated_left(&invite.mac).ok()?;
+        Some(invite)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_round_trips() {
+        let chat_id = Uuid::new_v4();
+        let code = InviteCode { chat_id, invite_id: 42, mac: [7; INVITE_MAC_LEN] };
+        let decoded = InviteCode::decode(&code.encode()).unwrap();
+        assert_eq!((decoded.chat_id, decoded.invite_id, decoded.mac), (chat_id, 42, [7; INVITE_MAC_LEN]));
+        assert!(InviteCode::decode(&format!("  {}\n", code.encode())).is_some());
+    }
+
+    #[test]
+    fn verifies_its_own_codes() {
+        let key = InviteKey::generate().unwrap();
+        let chat_id = Uuid::new_v4();
+        let invite = key.verify(&key.sign(chat_id, 3)).unwrap();
+        assert_eq!((invite.chat_id, invite.invite_id), (chat_id, 3));
+    }
+
+    #[test]
+    fn rejects_tampered_codes() {
+        let key = InviteKey::generate().unwrap();
+        let signed = key.verify(&key.sign(Uuid::new_v4(), 3)).unwrap();
+        for i in [0, INVITE_MAC_LEN - 1] {
+            let mut mac = signed.mac;
+            mac[i] ^= 1;
+            assert!(key.verify(&InviteCode { mac, ..signed }.encode()).is_none(), "mac byte {i} flipped");
+        }
+        assert!(key.verify("not an invite").is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_codes() {
+        let key = InviteKey::generate().unwrap();
+        let code = key.sign(Uuid::new_v4(), 3);
+        for len in [0, 32, code.len() - 2, code.len() - 1] {
+            assert!(key.verify(&code[..len]).is_none(), "cut to {len} chars");
+        }
+    }
+
+    #[test]
+    fn rejects_another_keys_codes() {
+        let (key, other) = (InviteKey::generate().unwrap(), InviteKey::generate().unwrap());
+        assert!(key.verify(&other.sign(Uuid::new_v4(), 3)).is_none());
+    }
+
+    #[test]
+    fn rejects_a_mac_moved_to_another_chat() {
+        let key = InviteKey::generate().unwrap();
+        let signed = key.verify(&key.sign(Uuid::new_v4(), 3)).unwrap();
+        let moved = InviteCode { chat_id: Uuid::new_v4(), ..signed };
+        assert!(key.verify(&moved.encode()).is_none());
+        let renumbered = InviteCode { invite_id: 4, ..signed };
+        assert!(key.verify(&renumbered.encode()).is_none());
+    }
+}
@@ -0,0 +1,23 @@
+use protocol::DynError;
+use tracing_subscriber::{fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry};
+
+use crate::config::{LogFormat, ServerConfig};
+
+// lets the admin socket swap the log filter on a config reload
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+pub fn init(config: &ServerConfig) -> Result<LogHandle, DynError> {
+    let (filter, handle) = reload::Layer::new(EnvFilter::try_new(&config.log_level)?);
+    let (pretty, json) = match config.log_format {
+        LogFormat::Pretty => (Some(fmt::layer()), None),
+        LogFormat::Json => (None, Some(fmt::layer().json().with_current_span(true).with_span_list(true))),
+    };
+
+    tracing_subscriber::registry().with(filter).with(pretty).with(json).try_init()?;
+    Ok(handle)
+}
+
+pub fn set_level(handle: &LogHandle, level: &str) -> Result<(), DynError> {
+    handle.reload(EnvFilter::try_new(level)?)?;
+    Ok(())
+}
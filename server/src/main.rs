@@ -1,11 +1,26 @@
-use protocol;
+mod admin;
+mod config;
+mod invites;
+mod logging;
 mod server;
+mod stats;
+
+use config::ServerConfig;
+use invites::InviteKey;
+use tracing::info;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let port: i32 = 8080;
-    println!("Starting server on port {port}!");
-    let server = server::ChatServer::new(port);
+    let config = ServerConfig::from_env()?;
+    let log_handle = logging::init(&config)?;
+
+    if let Some(metrics_port) = config.metrics_port {
+        stats::install(metrics_port)?;
+        info!(port = metrics_port, "serving metrics");
+    }
+
+    info!(port = config.port, "starting server");
+    let server = server::ChatServer::new(config, log_handle, InviteKey::generate()?);
     server.run().await?;
     Ok(())
 }
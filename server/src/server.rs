@@ -4,24 +4,63 @@ use argon2::{
 };
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     io::ErrorKind,
+    net::SocketAddr,
     sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::{broadcast, Mutex},
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc, Mutex,
+    },
+    time::{self, Instant, MissedTickBehavior},
 };
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 
-use crate::protocol::{
-    read_message, write_message, ChatMessage, CreateChatResponse, DynError, ErrorCode, ErrorResponse, JoinChatResponse, LeaveChatResponse, Packet,
+use protocol::{
+    read_message, write_message, ChatExpiring, ChatMessage, ConnectionSummary, CreateChatResponse, CreateInviteResponse, DeleteMessageResponse,
+    DynError, EditMessageResponse, ErrorCode, ErrorResponse, FetchHistoryResponse, FetchThreadResponse, InviteInfo, JoinChatResponse,
+    LeaveChatResponse, ListInvitesResponse, ListRoomsResponse, MarkReadResponse, MemberEvent, MessageDeleted, Packet, PasswordChanged, PinResponse,
+    Ping, PinsUpdated, Pong,
     ProtocolMessage::{self, *},
-    SendMessageResponse,
+    ReactionCount, ReactionResponse, ReactionsUpdated, ReadPosition, RemovedFromChat, RevokeInviteResponse, Role, RoomListing, RoomSummary,
+    SearchMessagesRequest, SearchMessagesResponse, SendMessageResponse, ServerNotice, SetRoleResponse, SetRoomPasswordResponse, SetTopicResponse,
+    TopicChanged, TypingUpdated, HEARTBEAT_INTERVAL, HEARTBEAT_TIMEOUT, MAX_ROOM_TTL, TYPING_TIMEOUT,
 };
 
 use rand::rngs::OsRng;
 use uuid::Uuid;
 
+use crate::{
+    admin,
+    config::ServerConfig,
+    invites::InviteKey,
+    logging::{self, LogHandle},
+    stats,
+};
+
+// how often the reaper looks for rooms to expire
+const REAPER_INTERVAL: Duration = Duration::from_secs(30);
+// and how often lapsed typing indicators are cleared
+const TYPING_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// how many of the latest messages a joining user gets replayed
+const REPLAY_LIMIT: usize = 50;
+const MAX_EMOJI_LEN: usize = 32;
+const MAX_TOPIC_LEN: usize = 200;
+const MAX_PINS: usize = 50;
+const MAX_INVITES: usize = 50;
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+const MAX_SEARCH_LIMIT: usize = 100;
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+const MAX_HISTORY_LIMIT: usize = 200;
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
 fn gen_chat_id() -> Uuid {
     Uuid::new_v4()
 }
@@ -38,166 +77,1208 @@ fn verify_password(password: &str, stored: &str) -> Result<(), DynError> {
     Ok(())
 }
 
+struct Invite {
+    code: String,
+    created_by: String,
+    expires_at: Option<u64>, // unix seconds, like everything the client sees
+    max_uses: Option<u32>,
+    uses: u32,
+}
+
+impl Invite {
+    fn usable(&self, now: u64) -> Result<(), ErrorResponse> {
+        if self.expires_at.is_some_and(|at| at <= now) {
+            return Err(ErrorResponse { code: ErrorCode::InviteExpired, message: "Invite has expired".into() });
+        }
+        if self.max_uses.is_some_and(|max| self.uses >= max) {
+            return Err(ErrorResponse { code: ErrorCode::InviteExpired, message: "Invite has been used up".into() });
+        }
+        Ok(())
+    }
+
+    fn info(&self, id: u64) -> InviteInfo {
+        InviteInfo { id, code: self.code.clone(), created_by: self.created_by.clone(), expires_at: self.expires_at, max_uses: self.max_uses, uses: self.uses }
+    }
+}
+
+// how a member got past the password, so rotating it can remove the ones who used the old one
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum JoinedWith {
+    NoPassword,
+    Password,
+    Invite,
+}
+
 struct ChatRoom {
     tokens: HashMap<Uuid, String>, // token to username
     users: HashSet<String>,
+    joined_with: HashMap<String, JoinedWith>, // same keys as users
     password: Option<String>,
-    messages: Vec<ChatMessage>, // TODO: currently not using this but maybe later use it to show previous messages to late joiners
-    broadcaster: broadcast::Sender<ChatMessage>,
+    messages: Vec<ChatMessage>, // reactions aren't kept on these, see below
+    next_message_id: u64,
+    reactions: HashMap<u64, BTreeMap<String, BTreeSet<String>>>, // message id to emoji to who reacted
+    read_positions: HashMap<String, u64>, // username to last message id they've seen, kept after they leave
+    typing: HashMap<String, Instant>,     // username to when their indicator lapses unless refreshed
+    topic: Option<String>,
+    pins: Vec<u64>, // message ids, in the order they were pinned
+    invites: BTreeMap<u64, Invite>,
+    next_invite_id: u64,
+    broadcaster: broadcast::Sender<ProtocolMessage>,
+
+    // roles, keyed by username like everything else in here. a name is held by whoever has its
+    // member key, so the owner's and moderators' names can't be taken over by someone else and
+    // messages can only be edited with the key they were written with
+    creator: u64, // connection that created the room, its first join makes it the owner
+    owner: Option<String>,
+    moderators: HashSet<String>,
+    member_keys: HashMap<String, Uuid>,
+    authors: HashMap<u64, Uuid>, // message id to the member key it was sent with
+
+    // bookkeeping for the reaper
+    last_activity: Instant,
+    empty_since: Option<Instant>,
+    expires_at: Option<Instant>, // only set for ephemeral rooms
+    warned_for: Option<Instant>, // the deadline members were last warned about
 }
 
 impl ChatRoom {
-    fn new(password: Option<String>) -> Self {
+    fn new(password: Option<String>, ttl: Option<Duration>, creator: u64) -> Self {
         let (broadcaster, _) = broadcast::channel(100);
+        let now = Instant::now();
+
+        ChatRoom {
+            tokens: HashMap::new(),
+            users: HashSet::new(),
+            joined_with: HashMap::new(),
+            password,
+            messages: Vec::new(),
+            next_message_id: 1,
+            reactions: HashMap::new(),
+            read_positions: HashMap::new(),
+            typing: HashMap::new(),
+            topic: None,
+            pins: Vec::new(),
+            invites: BTreeMap::new(),
+            next_invite_id: 1,
+            broadcaster,
+            creator,
+            owner: None,
+            moderators: HashSet::new(),
+            member_keys: HashMap::new(),
+            authors: HashMap::new(),
+            last_activity: now,
+            empty_since: Some(now),
+            expires_at: ttl.and_then(|ttl| now.checked_add(ttl)),
+            warned_for: None,
+        }
+    }
+
+    // earliest point any of the expiry policies wants this room gone, and why
+    fn expiry(&self, config: &ServerConfig) -> Option<(Instant, &'static str)> {
+        let ephemeral = self.expires_at.map(|at| (at, "Temporary room reached the end of its lifetime"));
+        // too far off to represent is the same as never
+        let idle = config.idle_room_ttl.and_then(|ttl| self.last_activity.checked_add(ttl)).map(|at| (at, "Room was idle for too long"));
+        let empty = config.empty_room_ttl.zip(self.empty_since).and_then(|(ttl, since)| since.checked_add(ttl)).map(|at| (at, "Room was empty for too long"));
+
+        [ephemeral, idle, empty].into_iter().flatten().min_by_key(|(at, _)| *at)
+    }
+
+    fn username(&self, token: Uuid) -> Result<&String, ErrorResponse> {
+        self.tokens.get(&token).ok_or_else(|| ErrorResponse { code: ErrorCode::Unauthorized, message: "User does not exist in the room".into() })
+    }
 
-        ChatRoom { tokens: HashMap::new(), users: HashSet::new(), password, messages: Vec::new(), broadcaster }
+    fn role(&self, username: &str) -> Role {
+        if self.owner.as_deref() == Some(username) {
+            Role::Owner
+        } else if self.moderators.contains(username) {
+            Role::Moderator
+        } else {
+            Role::Member
+        }
     }
 
-    fn join(&mut self, username: String, password: Option<String>) -> Result<(Uuid, broadcast::Receiver<ChatMessage>), ErrorResponse> {
+    // invite is the id out of a code the caller already checked the signature of
+    // returns the token and member key
+    fn join(&mut self, username: String, password: Option<String>, invite: Option<u64>, member_key: Option<Uuid>, connection_id: u64) -> Result<(Uuid, Uuid, Role, broadcast::Receiver<ProtocolMessage>), ErrorResponse> {
         if self.users.contains(&username) {
             return Err(ErrorResponse { code: ErrorCode::UserAlreadyInRoom, message: "User already in room!".into() });
         }
+        // anyone else's name goes to whoever joins with it next, under a new key
+        let member_key = match (self.member_keys.get(&username), member_key) {
+            (Some(held), Some(key)) if *held == key => key,
+            (Some(_), _) if self.role(&username) != Role::Member => {
+                return Err(ErrorResponse { code: ErrorCode::Forbidden, message: "That name belongs to the room's owner or a moderator".into() });
+            }
+            _ => Uuid::new_v4(),
+        };
 
-        if let Some(room_pw_hash) = &self.password {
+        let joined_with = if let Some(invite_id) = invite {
+            let invite = self.invites.get_mut(&invite_id).ok_or_else(|| ErrorResponse { code: ErrorCode::InvalidInvite, message: "Invite was revoked".into() })?;
+            invite.usable(unix_now())?;
+            invite.uses += 1;
+            JoinedWith::Invite
+        } else if let Some(room_pw_hash) = &self.password {
             let pw = password.ok_or_else(|| ErrorResponse { code: ErrorCode::PasswordMissing, message: "Password missing".into() })?;
 
             verify_password(&pw, room_pw_hash).map_err(|_| ErrorResponse { code: ErrorCode::WrongPassword, message: "Wrong password".into() })?;
+            JoinedWith::Password
+        } else {
+            JoinedWith::NoPassword
+        };
+
+        if self.owner.is_none() && self.creator == connection_id {
+            self.owner = Some(username.clone());
         }
 
         let token = Uuid::new_v4();
+        let role = self.role(&username);
+        // announced before subscribing so the new member doesn't hear about itself
+        let _ = self.broadcaster.send(MemberJoined(MemberEvent { username: username.clone() }));
         self.tokens.insert(token, username.clone());
+        self.member_keys.insert(username.clone(), member_key);
+        self.joined_with.insert(username.clone(), joined_with);
         self.users.insert(username);
+        self.last_activity = Instant::now();
+        self.empty_since = None;
         let receiver = self.broadcaster.subscribe();
 
-        Ok((token, receiver))
+        Ok((token, member_key, role, receiver))
     }
 
-    fn add_message(&mut self, token: Uuid, message: String) -> Result<(), ErrorResponse> {
-        let username = self.tokens.get(&token).ok_or_else(|| ErrorResponse { code: ErrorCode::Unauthorized, message: "User does not exist in the room".into() })?;
-        self.messages.push(ChatMessage { username: username.into(), message: message.clone() });
+    // by the member key rather than the name, which may have changed hands since
+    fn is_author(&self, username: &str, message_id: u64) -> bool {
+        self.member_keys.get(username).is_some_and(|key| self.authors.get(&message_id) == Some(key))
+    }
+
+    fn add_message(&mut self, token: Uuid, message: String, reply_to: Option<u64>) -> Result<u64, ErrorResponse> {
+        let username = self.username(token)?.clone();
+        if let Some(parent) = reply_to {
+            self.find_message(parent)?;
+        }
+
+        let id = self.next_message_id;
+        self.next_message_id += 1;
 
-        let _ = self.broadcaster.send(ChatMessage { username: username.clone(), message });
+        // the message itself tells everyone they're done typing
+        self.typing.remove(&username);
+        let msg = ChatMessage { id, sent_at: unix_now(), username, message, edited: false, reply_to, reactions: Vec::new() };
+        self.messages.push(msg.clone());
+        if let Some(key) = self.member_keys.get(&msg.username) {
+            self.authors.insert(id, *key);
+        }
+        self.last_activity = Instant::now();
+
+        let _ = self.broadcaster.send(MessageBroadcast(msg));
+
+        Ok(id)
+    }
 
+    fn find_message(&self, message_id: u64) -> Result<usize, ErrorResponse> {
+        // ids only ever go up, so the history stays sorted even with deletions
+        self.messages.binary_search_by_key(&message_id, |m| m.id).map_err(|_| ErrorResponse { code: ErrorCode::MessageNotFound, message: "Message not found".into() })
+    }
+
+    fn reaction_counts(&self, message_id: u64) -> Vec<ReactionCount> {
+        let Some(by_emoji) = self.reactions.get(&message_id) else {
+            return Vec::new();
+        };
+        by_emoji.iter().map(|(emoji, users)| ReactionCount { emoji: emoji.clone(), count: users.len() }).collect()
+    }
+
+    // a copy of a stored message with its current reactions filled in, for anything sent to clients
+    fn with_reactions(&self, msg: &ChatMessage) -> ChatMessage {
+        ChatMessage { reactions: self.reaction_counts(msg.id), ..msg.clone() }
+    }
+
+    // up to limit messages older than before_seq, oldest first, plus the cursor for the page before that
+    fn history_page(&self, before_seq: Option<u64>, limit: usize) -> (Vec<ChatMessage>, Option<u64>) {
+        let end = match before_seq {
+            Some(seq) => self.messages.partition_point(|m| m.id < seq),
+            None => self.messages.len(),
+        };
+        let start = end.saturating_sub(limit);
+
+        let page = self.messages[start..end].iter().map(|m| self.with_reactions(m)).collect();
+        let next_before_seq = if start > 0 { Some(self.messages[start].id) } else { None };
+        (page, next_before_seq)
+    }
+
+    fn react(&mut self, token: Uuid, message_id: u64, emoji: String, add: bool) -> Result<(), ErrorResponse> {
+        let username = self.username(token)?.clone();
+        self.find_message(message_id)?;
+        if emoji.is_empty() || emoji.len() > MAX_EMOJI_LEN || emoji.contains(char::is_whitespace) {
+            return Err(ErrorResponse { code: ErrorCode::InvalidFormat, message: "Reactions must be a single emoji or short code".into() });
+        }
+
+        let by_emoji = self.reactions.entry(message_id).or_default();
+        if add {
+            by_emoji.entry(emoji).or_default().insert(username);
+        } else if let Some(users) = by_emoji.get_mut(&emoji) {
+            users.remove(&username);
+            if users.is_empty() {
+                by_emoji.remove(&emoji);
+            }
+        }
+        if by_emoji.is_empty() {
+            self.reactions.remove(&message_id);
+        }
+
+        let _ = self.broadcaster.send(ReactionsUpdated(ReactionsUpdated { message_id, reactions: self.reaction_counts(message_id) }));
+        Ok(())
+    }
+
+    // returns a page of matches, newest first, and the cursor for the next page if there is one
+    fn search(&self, req: &SearchMessagesRequest) -> Result<(Vec<ChatMessage>, Option<u64>), ErrorResponse> {
+        self.username(req.token)?;
+
+        let limit = req.limit.unwrap_or(DEFAULT_SEARCH_LIMIT).clamp(1, MAX_SEARCH_LIMIT);
+        let query = req.query.as_deref().map(str::to_lowercase).filter(|q| !q.is_empty());
+        let mut matches = self
+            .messages
+            .iter()
+            .rev()
+            .filter(|m| req.before_id.is_none_or(|before| m.id < before))
+            .filter(|m| req.author.as_ref().is_none_or(|author| &m.username == author))
+            .filter(|m| req.since.is_none_or(|since| m.sent_at >= since))
+            .filter(|m| req.until.is_none_or(|until| m.sent_at < until))
+            .filter(|m| query.as_ref().is_none_or(|q| m.message.to_lowercase().contains(q)));
+
+        let results: Vec<ChatMessage> = matches.by_ref().take(limit).map(|m| self.with_reactions(m)).collect();
+        let next_before_id = match matches.next() {
+            Some(_) => results.last().map(|m| m.id),
+            None => None,
+        };
+        Ok((results, next_before_id))
+    }
+
+    fn unread(&self, username: &str) -> usize {
+        let last_read = self.read_positions.get(username).copied().unwrap_or(0);
+        self.messages.len() - self.messages.partition_point(|m| m.id <= last_read)
+    }
+
+    fn all_read_positions(&self) -> Vec<ReadPosition> {
+        let mut positions: Vec<ReadPosition> = self.read_positions.iter().map(|(username, seq)| ReadPosition { username: username.clone(), seq: *seq }).collect();
+        positions.sort_by(|a, b| a.username.cmp(&b.username));
+        positions
+    }
+
+    fn mark_read(&mut self, token: Uuid, seq: u64) -> Result<(), ErrorResponse> {
+        let username = self.username(token)?.clone();
+
+        // positions only move forward and never past the newest message
+        let seq = seq.min(self.next_message_id - 1);
+        let current = self.read_positions.entry(username.clone()).or_insert(0);
+        if seq > *current {
+            *current = seq;
+            let _ = self.broadcaster.send(ReadPositionUpdated(ReadPosition { username, seq }));
+        }
+        Ok(())
+    }
+
+    // only changes are broadcast, refreshing an indicator that's already up just pushes its deadline back
+    fn set_typing(&mut self, token: Uuid, active: bool) -> Result<(), ErrorResponse> {
+        let username = self.username(token)?.clone();
+        if active {
+            if self.typing.insert(username.clone(), Instant::now() + TYPING_TIMEOUT).is_none() {
+                let _ = self.broadcaster.send(TypingUpdated(TypingUpdated { username, active: true }));
+            }
+        } else {
+            self.stop_typing(&username);
+        }
+        Ok(())
+    }
+
+    fn stop_typing(&mut self, username: &str) {
+        if self.typing.remove(username).is_some() {
+            let _ = self.broadcaster.send(TypingUpdated(TypingUpdated { username: username.into(), active: false }));
+        }
+    }
+
+    // for clients that went quiet without saying they stopped
+    fn expire_typing(&mut self, now: Instant) {
+        let lapsed: Vec<String> = self.typing.iter().filter(|(_, until)| **until <= now).map(|(username, _)| username.clone()).collect();
+        for username in lapsed {
+            self.stop_typing(&username);
+        }
+    }
+
+    fn listing(&self, chat_id: Uuid, username: &str) -> RoomListing {
+        RoomListing { chat_id, members: self.users.len(), unread: self.unread(username), last_message_at: self.messages.last().map(|m| m.sent_at) }
+    }
+
+    fn thread(&self, token: Uuid, root_id: u64) -> Result<(ChatMessage, Vec<ChatMessage>), ErrorResponse> {
+        self.username(token)?;
+        let idx = self.find_message(root_id)?;
+
+        // replies always come after their parent, so one pass in id order picks up every level
+        let mut in_thread = HashSet::from([root_id]);
+        let mut replies = Vec::new();
+        for msg in &self.messages[idx + 1..] {
+            if msg.reply_to.is_some_and(|parent| in_thread.contains(&parent)) {
+                in_thread.insert(msg.id);
+                replies.push(self.with_reactions(msg));
+            }
+        }
+
+        Ok((self.with_reactions(&self.messages[idx]), replies))
+    }
+
+    fn edit_message(&mut self, token: Uuid, message_id: u64, message: String) -> Result<(), ErrorResponse> {
+        let username = self.username(token)?.clone();
+        let idx = self.find_message(message_id)?;
+
+        if !self.is_author(&username, message_id) {
+            return Err(ErrorResponse { code: ErrorCode::Forbidden, message: "You can only edit your own messages".into() });
+        }
+        let msg = &mut self.messages[idx];
+        msg.message = message;
+        msg.edited = true;
+
+        let _ = self.broadcaster.send(MessageEdited(self.with_reactions(&self.messages[idx])));
+        Ok(())
+    }
+
+    fn delete_message(&mut self, token: Uuid, message_id: u64) -> Result<(), ErrorResponse> {
+        let username = self.username(token)?.clone();
+        let idx = self.find_message(message_id)?;
+
+        if !self.is_author(&username, message_id) && self.role(&username) == Role::Member {
+            return Err(ErrorResponse { code: ErrorCode::Forbidden, message: "Only moderators can delete other people's messages".into() });
+        }
+        self.messages.remove(idx);
+        self.reactions.remove(&message_id);
+        self.authors.remove(&message_id);
+        self.pins.retain(|&id| id != message_id); // clients drop it from their pins on MessageDeleted
+
+        let _ = self.broadcaster.send(MessageDeleted(MessageDeleted { message_id, deleted_by: username }));
+        Ok(())
+    }
+
+    // owner or moderator, for changing the room itself
+    fn privileged(&self, token: Uuid, action: &str) -> Result<String, ErrorResponse> {
+        let username = self.username(token)?;
+        if self.role(username) == Role::Member {
+            return Err(ErrorResponse { code: ErrorCode::Forbidden, message: format!("Only the owner and moderators can {action}") });
+        }
+        Ok(username.clone())
+    }
+
+    fn set_topic(&mut self, token: Uuid, topic: Option<String>) -> Result<(), ErrorResponse> {
+        let username = self.privileged(token, "change the topic")?;
+        let topic = topic.map(|t| t.trim().to_owned()).filter(|t| !t.is_empty());
+        if topic.as_ref().is_some_and(|t| t.chars().count() > MAX_TOPIC_LEN) {
+            return Err(ErrorResponse { code: ErrorCode::InvalidFormat, message: format!("Topic can be at most {MAX_TOPIC_LEN} characters") });
+        }
+
+        self.topic = topic.clone();
+        let _ = self.broadcaster.send(TopicChanged(TopicChanged { topic, changed_by: username }));
+        Ok(())
+    }
+
+    fn create_invite(&mut self, token: Uuid, key: &InviteKey, chat_id: Uuid, expires_in_secs: Option<u64>, max_uses: Option<u32>) -> Result<InviteInfo, ErrorResponse> {
+        let created_by = self.privileged(token, "create invites")?;
+        let now = unix_now();
+        self.invites.retain(|_, invite| invite.usable(now).is_ok());
+        if self.invites.len() >= MAX_INVITES {
+            return Err(ErrorResponse { code: ErrorCode::InvalidFormat, message: format!("A room can have at most {MAX_INVITES} open invites") });
+        }
+        if max_uses == Some(0) {
+            return Err(ErrorResponse { code: ErrorCode::InvalidFormat, message: "An invite needs at least one use".into() });
+        }
+
+        let expires_at = match expires_in_secs {
+            Some(secs) => Some(now.checked_add(secs).ok_or_else(|| ErrorResponse { code: ErrorCode::InvalidFormat, message: "Invite expiry is too far off".into() })?),
+            None => None,
+        };
+
+        let id = self.next_invite_id;
+        self.next_invite_id += 1;
+        let invite = Invite { code: key.sign(chat_id, id), created_by, expires_at, max_uses, uses: 0 };
+        let info = invite.info(id);
+        self.invites.insert(id, invite);
+        Ok(info)
+    }
+
+    // expired and used up invites are dropped here rather than by the reaper
+    fn list_invites(&mut self, token: Uuid) -> Result<Vec<InviteInfo>, ErrorResponse> {
+        self.privileged(token, "see invites")?;
+        let now = unix_now();
+        self.invites.retain(|_, invite| invite.usable(now).is_ok());
+        Ok(self.invites.iter().map(|(id, invite)| invite.info(*id)).collect())
+    }
+
+    fn revoke_invite(&mut self, token: Uuid, invite_id: u64) -> Result<(), ErrorResponse> {
+        self.privileged(token, "revoke invites")?;
+        match self.invites.remove(&invite_id) {
+            Some(_) => Ok(()),
+            None => Err(ErrorResponse { code: ErrorCode::InvalidInvite, message: "Invite not found".into() }),
+        }
+    }
+
+    fn pinned_messages(&self) -> Vec<ChatMessage> {
+        self.pins.iter().filter_map(|&id| self.find_message(id).ok()).map(|idx| self.with_reactions(&self.messages[idx])).collect()
+    }
+
+    // pinning something already pinned (or unpinning something that isn't) is a no-op
+    fn set_pinned(&mut self, token: Uuid, message_id: u64, pinned: bool) -> Result<(), ErrorResponse> {
+        let username = self.privileged(token, if pinned { "pin messages" } else { "unpin messages" })?;
+        self.find_message(message_id)?;
+
+        let is_pinned = self.pins.contains(&message_id);
+        if pinned == is_pinned {
+            return Ok(());
+        }
+        if pinned {
+            if self.pins.len() >= MAX_PINS {
+                return Err(ErrorResponse { code: ErrorCode::InvalidFormat, message: format!("A room can have at most {MAX_PINS} pinned messages") });
+            }
+            self.pins.push(message_id);
+        } else {
+            self.pins.retain(|&id| id != message_id);
+        }
+
+        let _ = self.broadcaster.send(PinsUpdated(PinsUpdated { message_id, pinned, changed_by: username, pins: self.pinned_messages() }));
+        Ok(())
+    }
+
+    fn owner_only(&self, token: Uuid, action: &str) -> Result<String, ErrorResponse> {
+        let username = self.username(token)?;
+        if self.role(username) != Role::Owner {
+            return Err(ErrorResponse { code: ErrorCode::Forbidden, message: format!("Only the room owner can {action}") });
+        }
+        Ok(username.clone())
+    }
+
+    // password is already hashed, the caller checks owner_only first so nobody else gets to make us hash.
+    // returns who was kicked, their connections still have to be told
+    fn set_password(&mut self, token: Uuid, password: Option<String>, kick_old: bool) -> Result<Vec<String>, ErrorResponse> {
+        let changed_by = self.owner_only(token, "change the password")?;
+        self.password = password;
+
+        let mut kicked = Vec::new();
+        if kick_old {
+            kicked = self.joined_with.iter().filter(|(name, with)| **with == JoinedWith::Password && **name != changed_by).map(|(name, _)| name.clone()).collect();
+            kicked.sort();
+            for username in &kicked {
+                self.remove_member(username);
+            }
+        }
+
+        let _ = self.broadcaster.send(PasswordChanged(PasswordChanged { changed_by, password_protected: self.password.is_some(), kicked: kicked.clone() }));
+        Ok(kicked)
+    }
+
+    fn set_role(&mut self, token: Uuid, username: String, role: Role) -> Result<(), ErrorResponse> {
+        let requester = self.username(token)?;
+        if self.role(requester) != Role::Owner {
+            return Err(ErrorResponse { code: ErrorCode::Forbidden, message: "Only the room owner can change roles".into() });
+        }
+        // a role only means something once the name is bound to a member key, otherwise whoever
+        // took the name first would get it
+        if !self.member_keys.contains_key(&username) {
+            return Err(ErrorResponse { code: ErrorCode::InvalidFormat, message: format!("{username} has never joined this room") });
+        }
+
+        match role {
+            Role::Moderator => {
+                self.moderators.insert(username);
+            }
+            Role::Member => {
+                self.moderators.remove(&username);
+            }
+            Role::Owner => return Err(ErrorResponse { code: ErrorCode::InvalidFormat, message: "Ownership can't be handed out".into() }),
+        }
         Ok(())
     }
 
     fn leave(&mut self, token: Uuid) -> Result<(), ErrorResponse> {
-        let username = self.tokens.get(&token).ok_or_else(|| ErrorResponse { code: ErrorCode::Unauthorized, message: "User does not exist in the room".into() })?;
-        self.users.remove(username);
-        self.tokens.remove(&token);
+        let username = self.username(token)?.clone();
+        self.remove_member(&username);
         Ok(())
     }
+
+    fn remove_member(&mut self, username: &str) {
+        self.stop_typing(username);
+        if self.users.remove(username) {
+            let _ = self.broadcaster.send(MemberLeft(MemberEvent { username: username.into() }));
+        }
+        self.joined_with.remove(username);
+        self.tokens.retain(|_, name| name != username);
+        if self.users.is_empty() {
+            self.empty_since = Some(Instant::now());
+        }
+    }
+}
+
+// things other tasks (mostly the admin socket) can tell a connection to do
+enum Control {
+    Notice(String),
+    Expiring(ChatExpiring),
+    RemovedFromChat { chat_id: Uuid, reason: String },
+    Disconnect(String),
+}
+
+struct Connection {
+    peer: SocketAddr,
+    connected_at: Instant,
+    username: Option<String>,
+    chat_id: Option<Uuid>,
+    control: mpsc::UnboundedSender<Control>,
 }
 
 pub struct ChatServer {
-    port: i32,
+    config: ServerConfig,
+    log_handle: LogHandle,
     chats: HashMap<Uuid, ChatRoom>, // ChatId to Chat
+    invite_key: InviteKey,
+    connections: HashMap<u64, Connection>,
+    next_connection_id: u64,
 }
 
 impl ChatServer {
-    pub fn new(port: i32) -> Self {
-        ChatServer { port, chats: HashMap::new() }
+    pub fn new(config: ServerConfig, log_handle: LogHandle, invite_key: InviteKey) -> Self {
+        ChatServer { config, log_handle, chats: HashMap::new(), invite_key, connections: HashMap::new(), next_connection_id: 1 }
     }
 
     pub async fn run(self) -> Result<(), DynError> {
-        let addr = format!("0.0.0.0:{}", self.port);
+        let addr = format!("0.0.0.0:{}", self.config.port);
 
-        let listener = TcpListener::bind(addr).await?;
+        let listener = TcpListener::bind(&addr).await?;
+        info!(%addr, "listening");
+        let admin_socket = self.config.admin_socket.clone();
         let state = Arc::new(Mutex::new(self));
 
-        loop {
-            let (socket, _) = listener.accept().await?;
-            let copy = Arc::clone(&state);
+        let reaper_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut ticker = time::interval(REAPER_INTERVAL);
+            loop {
+                ticker.tick().await;
+                reaper_state.lock().await.expire_rooms();
+            }
+        });
+
+        let typing_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut ticker = time::interval(TYPING_SWEEP_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let now = Instant::now();
+                for chat in typing_state.lock().await.chats.values_mut() {
+                    chat.expire_typing(now);
+                }
+            }
+        });
 
+        if let Some(path) = admin_socket {
+            let copy = Arc::clone(&state);
             tokio::spawn(async move {
-                if let Err(e) = handle_connection(socket, copy).await {
-                    eprintln!("an error occured:  {:?}", e);
+                if let Err(e) = admin::serve(path, copy).await {
+                    error!(error = %e, "admin socket failed");
                 }
             });
         }
+
+        loop {
+            let (socket, peer) = listener.accept().await?;
+            let copy = Arc::clone(&state);
+
+            // username and chat_id get filled in once the connection joins a room
+            let span = info_span!("connection", %peer, username = field::Empty, chat_id = field::Empty);
+            tokio::spawn(
+                async move {
+                    info!("client connected");
+                    stats::connection_opened();
+                    match handle_connection(socket, peer, copy).await {
+                        Ok(()) => info!("client disconnected"),
+                        Err(e) => warn!(error = %e, "connection closed with an error"),
+                    }
+                    stats::connection_closed();
+                }
+                .instrument(span),
+            );
+        }
+    }
+
+    fn register_connection(&mut self, peer: SocketAddr) -> (u64, mpsc::UnboundedReceiver<Control>) {
+        let id = self.next_connection_id;
+        self.next_connection_id += 1;
+
+        let (control, receiver) = mpsc::unbounded_channel();
+        self.connections.insert(id, Connection { peer, connected_at: Instant::now(), username: None, chat_id: None, control });
+        (id, receiver)
+    }
+
+    fn set_connection_chat(&mut self, id: u64, chat: Option<(Uuid, String)>) {
+        if let Some(conn) = self.connections.get_mut(&id) {
+            (conn.chat_id, conn.username) = chat.unzip();
+        }
+    }
+
+    pub fn room_summaries(&self) -> Vec<RoomSummary> {
+        self.chats
+            .iter()
+            .map(|(chat_id, chat)| {
+                let mut members: Vec<String> = chat.users.iter().cloned().collect();
+                members.sort();
+                RoomSummary { chat_id: *chat_id, members, password_protected: chat.password.is_some(), message_count: chat.messages.len() }
+            })
+            .collect()
+    }
+
+    pub fn connection_summaries(&self) -> Vec<ConnectionSummary> {
+        let mut summaries: Vec<ConnectionSummary> = self
+            .connections
+            .iter()
+            .map(|(id, conn)| ConnectionSummary {
+                connection_id: *id,
+                peer: conn.peer.to_string(),
+                username: conn.username.clone(),
+                chat_id: conn.chat_id,
+                connected_secs: conn.connected_at.elapsed().as_secs(),
+            })
+            .collect();
+        summaries.sort_by_key(|c| c.connection_id);
+        summaries
+    }
+
+    // drops the room and tells everyone who was in it, returns false if there was no such room
+    pub fn delete_room(&mut self, chat_id: Uuid, reason: &str) -> bool {
+        let Some(chat) = self.chats.remove(&chat_id) else {
+            return false;
+        };
+        for conn in self.connections.values_mut().filter(|c| c.chat_id == Some(chat_id)) {
+            conn.chat_id = None;
+            conn.username = None;
+            let _ = conn.control.send(Control::RemovedFromChat { chat_id, reason: reason.into() });
+        }
+        stats::rooms(self.chats.len());
+        stats::members_left(chat.users.len());
+        true
+    }
+
+    // the room has already dropped them, this tells their connections
+    fn removed_from_chat(&mut self, chat_id: Uuid, usernames: &[String], reason: &str) {
+        let removed = |c: &&mut Connection| c.chat_id == Some(chat_id) && c.username.as_ref().is_some_and(|name| usernames.contains(name));
+        for conn in self.connections.values_mut().filter(removed) {
+            conn.chat_id = None;
+            conn.username = None;
+            let _ = conn.control.send(Control::RemovedFromChat { chat_id, reason: reason.into() });
+        }
+    }
+
+    // deletes rooms past their expiry and warns members of rooms that are getting close
+    fn expire_rooms(&mut self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+
+        for (chat_id, chat) in self.chats.iter_mut() {
+            let Some((deadline, reason)) = chat.expiry(&self.config) else {
+                continue;
+            };
+            if deadline <= now {
+                expired.push((*chat_id, reason));
+                continue;
+            }
+
+            let expires_in = deadline - now;
+            if expires_in <= self.config.expiry_warning && chat.warned_for != Some(deadline) && !chat.users.is_empty() {
+                chat.warned_for = Some(deadline);
+                for conn in self.connections.values().filter(|c| c.chat_id == Some(*chat_id)) {
+                    let warning = ChatExpiring { chat_id: *chat_id, expires_in_secs: expires_in.as_secs(), reason: reason.into() };
+                    let _ = conn.control.send(Control::Expiring(warning));
+                }
+            }
+        }
+
+        for (chat_id, reason) in expired {
+            info!(%chat_id, reason, "room expired");
+            self.delete_room(chat_id, reason);
+        }
+    }
+
+    pub fn disconnect(&mut self, connection_id: u64, reason: &str) -> bool {
+        match self.connections.get(&connection_id) {
+            Some(conn) => conn.control.send(Control::Disconnect(reason.into())).is_ok(),
+            None => false,
+        }
+    }
+
+    // returns how many connections were using that name
+    pub fn disconnect_user(&mut self, username: &str, reason: &str) -> usize {
+        self.connections
+            .values()
+            .filter(|c| c.username.as_deref() == Some(username))
+            .filter(|c| c.control.send(Control::Disconnect(reason.into())).is_ok())
+            .count()
+    }
+
+    pub fn notice_all(&self, message: &str) -> usize {
+        self.connections.values().filter(|c| c.control.send(Control::Notice(message.into())).is_ok()).count()
+    }
+
+    // the log level and room expiry policies apply right away, anything else is reported back as needing a restart
+    pub fn reload_config(&mut self) -> Result<String, DynError> {
+        let new = ServerConfig::reload()?;
+        logging::set_level(&self.log_handle, &new.log_level)?;
+
+        let mut restart_needed = Vec::new();
+        if new.port != self.config.port {
+            restart_needed.push("PORT");
+        }
+        if new.log_format != self.config.log_format {
+            restart_needed.push("LOG_FORMAT");
+        }
+        if new.metrics_port != self.config.metrics_port {
+            restart_needed.push("METRICS_PORT");
+        }
+        if new.admin_socket != self.config.admin_socket {
+            restart_needed.push("ADMIN_SOCKET");
+        }
+
+        let summary = if restart_needed.is_empty() {
+            format!("config reloaded, log level is now {}", new.log_level)
+        } else {
+            format!("config reloaded, log level is now {}; restart to apply {}", new.log_level, restart_needed.join(", "))
+        };
+        self.config = new;
+        Ok(summary)
+    }
+}
+
+async fn handle_connection(socket: TcpStream, peer: SocketAddr, state: Arc<Mutex<ChatServer>>) -> Result<(), DynError> {
+    let (mut reader, mut writer) = socket.into_split();
+    let (connection_id, mut control) = state.lock().await.register_connection(peer);
+
+    // read_message isn't cancel safe so it can't sit in a select! next to the timers,
+    // reads happen on their own task and packets get handed over through a channel
+    let (packet_sender, mut packets) = mpsc::unbounded_channel::<Packet>();
+    let reader_task = tokio::spawn(async move {
+        while let Ok(pkt) = read_message(&mut reader).await {
+            if packet_sender.send(pkt).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut current_chat: Option<(Uuid, Uuid)> = None; // (chat_id, token)
+    let result = serve_connection(&mut writer, &mut packets, &mut control, connection_id, &state, &mut current_chat).await;
+    reader_task.abort();
+
+    let mut server = state.lock().await;
+    server.connections.remove(&connection_id);
+
+    // a dropped connection shouldn't keep holding its spot in the room
+    if let Some((chat_id, token)) = current_chat {
+        if let Some(chat) = server.chats.get_mut(&chat_id) {
+            if chat.leave(token).is_ok() {
+                info!("removed from chat after disconnect");
+                stats::members_left(1);
+            }
+        }
     }
+
+    result
 }
 
-async fn handle_connection(mut socket: tokio::net::TcpStream, state: Arc<Mutex<ChatServer>>) -> Result<(), DynError> {
-    let mut message_receiver: Option<broadcast::Receiver<ChatMessage>> = None;
-    let mut current_chat_id: Option<Uuid> = None;
+async fn serve_connection(
+    socket: &mut OwnedWriteHalf,
+    packets: &mut mpsc::UnboundedReceiver<Packet>,
+    control: &mut mpsc::UnboundedReceiver<Control>,
+    connection_id: u64,
+    state: &Arc<Mutex<ChatServer>>,
+    current_chat: &mut Option<(Uuid, Uuid)>,
+) -> Result<(), DynError> {
+    let mut message_receiver: Option<broadcast::Receiver<ProtocolMessage>> = None;
+    let mut closed_chat: Option<Uuid> = None; // its broadcast closed first, the RemovedFromChat saying why is still coming
+    let mut heartbeat = time::interval(HEARTBEAT_INTERVAL);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_seen = Instant::now();
 
     loop {
         tokio::select! {
-            result = read_message(&mut socket) => {
-                let packet = match result {
-                    Ok(pkt) => pkt,
-                    Err(_) => return Ok(()), // need to use OK instead of break
+            packet = packets.recv() => {
+                let Some(packet) = packet else {
+                    return Ok(()); // reader is gone, client closed the connection
                 };
+                last_seen = Instant::now();
+                let _timer = stats::RequestTimer::start(packet.message.kind());
 
                 let mut server = state.lock().await;
                 match packet.message {
                     CreateChatRequest(r) => {
+                        let ttl = r.ttl_secs.map(Duration::from_secs);
+                        if ttl.is_some_and(|ttl| ttl > MAX_ROOM_TTL) {
+                            send_error(socket, ErrorCode::InvalidFormat, &format!("A room can live for at most {} days", MAX_ROOM_TTL.as_secs() / (24 * 60 * 60))).await?;
+                            continue;
+                        }
                         let chat_id = gen_chat_id();
                         let hashed_pw = match r.password {
                             Some(pw) => Some(hash_password(pw)?),
                             None => None,
                         };
-                        server.chats.insert(chat_id, ChatRoom::new(hashed_pw));
+                        info!(%chat_id, password_protected = hashed_pw.is_some(), ttl_secs = r.ttl_secs, "chat created");
+                        server.chats.insert(chat_id, ChatRoom::new(hashed_pw, ttl, connection_id));
+                        stats::rooms(server.chats.len());
                         send_response(
-                            &mut socket,
+                            socket,
                             CreateChatResponse(CreateChatResponse { chat_id }),
                         )
                         .await?;
                     }
                     JoinChatRequest(r) => {
-                        if current_chat_id.is_some() {
-                            send_error(&mut socket, ErrorCode::UserAlreadyInAnotherRoom, "User already in another room!").await?;
+                        if current_chat.is_some() {
+                            send_error(socket, ErrorCode::UserAlreadyInAnotherRoom, "User already in another room!").await?;
                             continue;
                         }
 
+                        // a code for another room is as good as a forged one
+                        let invite = match r.invite {
+                            Some(ref code) => match server.invite_key.verify(code) {
+                                Some(invite) if invite.chat_id == r.chat_id => Some(invite.invite_id),
+                                _ => {
+                                    send_error(socket, ErrorCode::InvalidInvite, "Invalid invite").await?;
+                                    continue;
+                                }
+                            },
+                            None => None,
+                        };
+
                         let Some(chat) = server.chats.get_mut(&r.chat_id) else {
-                            send_error(&mut socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
                             continue;
                         };
 
-                        match chat.join(r.username.clone(), r.password) {
-                            Ok((token, receiver)) => {
+                        match chat.join(r.username.clone(), r.password, invite, r.member_key, connection_id) {
+                            Ok((token, member_key, role, receiver)) => {
                                 message_receiver = Some(receiver);
-                                current_chat_id = Some(r.chat_id);
-                                send_response(&mut socket, JoinChatResponse(JoinChatResponse { chat_id: r.chat_id, token, username: r.username })).await?;
+                                *current_chat = Some((r.chat_id, token));
+                                let span = Span::current();
+                                span.record("username", r.username.as_str());
+                                span.record("chat_id", field::display(r.chat_id));
+                                info!(?role, invite, "joined chat");
+                                stats::member_joined();
+                                let (history, next_before_seq) = chat.history_page(None, REPLAY_LIMIT);
+                                let last_read_seq = chat.read_positions.get(&r.username).copied();
+                                let unread = chat.unread(&r.username);
+                                let read_positions = chat.all_read_positions();
+                                let (topic, pins) = (chat.topic.clone(), chat.pinned_messages());
+                                let mut members: Vec<String> = chat.users.iter().cloned().collect();
+                                members.sort();
+                                server.set_connection_chat(connection_id, Some((r.chat_id, r.username.clone())));
+                                send_response(socket, JoinChatResponse(JoinChatResponse { chat_id: r.chat_id, token, username: r.username, role, history, next_before_seq, last_read_seq, unread, read_positions, topic, pins, members, member_key })).await?;
                             }
                             Err(err) => {
-                                send_error(&mut socket, err.code, &err.message).await?;
+                                send_error(socket, err.code, &err.message).await?;
                             }
                         }
                     }
                     SendMessageRequest(r) => {
                         let Some(chat) = server.chats.get_mut(&r.chat_id) else {
-                            send_error(&mut socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
                             continue;
                         };
-                        match chat.add_message(r.token, r.message) {
-                            Ok(()) => {
-                                send_response(&mut socket, SendMessageResponse(SendMessageResponse {}))
+                        let len = r.message.len();
+                        match chat.add_message(r.token, r.message, r.reply_to) {
+                            Ok(message_id) => {
+                                debug!(chat_id = %r.chat_id, message_id, reply_to = r.reply_to, len, "message sent");
+                                stats::message_sent();
+                                send_response(socket, SendMessageResponse(SendMessageResponse { message_id }))
                                     .await?;
                             }
                             Err(err) => {
-                                send_error(&mut socket, err.code, &err.message).await?;
+                                send_error(socket, err.code, &err.message).await?;
+                            }
+                        }
+                    }
+                    EditMessageRequest(r) => {
+                        let Some(chat) = server.chats.get_mut(&r.chat_id) else {
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            continue;
+                        };
+                        match chat.edit_message(r.token, r.message_id, r.message) {
+                            Ok(()) => {
+                                info!(chat_id = %r.chat_id, message_id = r.message_id, "message edited");
+                                send_response(socket, EditMessageResponse(EditMessageResponse {})).await?;
+                            }
+                            Err(err) => {
+                                send_error(socket, err.code, &err.message).await?;
+                            }
+                        }
+                    }
+                    DeleteMessageRequest(r) => {
+                        let Some(chat) = server.chats.get_mut(&r.chat_id) else {
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            continue;
+                        };
+                        match chat.delete_message(r.token, r.message_id) {
+                            Ok(()) => {
+                                info!(chat_id = %r.chat_id, message_id = r.message_id, "message deleted");
+                                send_response(socket, DeleteMessageResponse(DeleteMessageResponse {})).await?;
+                            }
+                            Err(err) => {
+                                send_error(socket, err.code, &err.message).await?;
+                            }
+                        }
+                    }
+                    MarkReadRequest(r) => {
+                        let Some(chat) = server.chats.get_mut(&r.chat_id) else {
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            continue;
+                        };
+                        match chat.mark_read(r.token, r.seq) {
+                            Ok(()) => {
+                                send_response(socket, MarkReadResponse(MarkReadResponse {})).await?;
+                            }
+                            Err(err) => {
+                                send_error(socket, err.code, &err.message).await?;
+                            }
+                        }
+                    }
+                    Typing(r) => {
+                        // nothing goes back, a stale one for a room we've since left is just dropped
+                        let Some((chat_id, token)) = *current_chat else {
+                            continue;
+                        };
+                        if let Some(chat) = server.chats.get_mut(&chat_id).filter(|_| chat_id == r.chat_id) {
+                            let _ = chat.set_typing(token, r.active);
+                        }
+                    }
+                    ListRoomsRequest(r) => {
+                        // only rooms the caller can show it was a member of, so ids and names alone don't give anything away
+                        let rooms = r
+                            .rooms
+                            .iter()
+                            .filter_map(|room| {
+                                let chat = server.chats.get(&room.chat_id)?;
+                                (chat.member_keys.get(&room.username) == Some(&room.member_key)).then(|| chat.listing(room.chat_id, &room.username))
+                            })
+                            .collect();
+                        send_response(socket, ListRoomsResponse(ListRoomsResponse { rooms })).await?;
+                    }
+                    FetchHistoryRequest(r) => {
+                        let Some(chat) = server.chats.get(&r.chat_id) else {
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            continue;
+                        };
+                        if let Err(err) = chat.username(r.token) {
+                            send_error(socket, err.code, &err.message).await?;
+                            continue;
+                        }
+                        let limit = r.limit.unwrap_or(DEFAULT_HISTORY_LIMIT).clamp(1, MAX_HISTORY_LIMIT);
+                        let (messages, next_before_seq) = chat.history_page(r.before_seq, limit);
+                        send_response(socket, FetchHistoryResponse(FetchHistoryResponse { messages, next_before_seq })).await?;
+                    }
+                    SearchMessagesRequest(r) => {
+                        let Some(chat) = server.chats.get(&r.chat_id) else {
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            continue;
+                        };
+                        match chat.search(&r) {
+                            Ok((results, next_before_id)) => {
+                                debug!(chat_id = %r.chat_id, found = results.len(), "search");
+                                send_response(socket, SearchMessagesResponse(SearchMessagesResponse { results, next_before_id })).await?;
+                            }
+                            Err(err) => {
+                                send_error(socket, err.code, &err.message).await?;
+                            }
+                        }
+                    }
+                    FetchThreadRequest(r) => {
+                        let Some(chat) = server.chats.get(&r.chat_id) else {
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            continue;
+                        };
+                        match chat.thread(r.token, r.message_id) {
+                            Ok((root, replies)) => {
+                                send_response(socket, FetchThreadResponse(FetchThreadResponse { root, replies })).await?;
+                            }
+                            Err(err) => {
+                                send_error(socket, err.code, &err.message).await?;
+                            }
+                        }
+                    }
+                    AddReactionRequest(r) => {
+                        let Some(chat) = server.chats.get_mut(&r.chat_id) else {
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            continue;
+                        };
+                        match chat.react(r.token, r.message_id, r.emoji, true) {
+                            Ok(()) => {
+                                send_response(socket, AddReactionResponse(ReactionResponse {})).await?;
+                            }
+                            Err(err) => {
+                                send_error(socket, err.code, &err.message).await?;
+                            }
+                        }
+                    }
+                    RemoveReactionRequest(r) => {
+                        let Some(chat) = server.chats.get_mut(&r.chat_id) else {
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            continue;
+                        };
+                        match chat.react(r.token, r.message_id, r.emoji, false) {
+                            Ok(()) => {
+                                send_response(socket, RemoveReactionResponse(ReactionResponse {})).await?;
+                            }
+                            Err(err) => {
+                                send_error(socket, err.code, &err.message).await?;
+                            }
+                        }
+                    }
+                    SetRoleRequest(r) => {
+                        let Some(chat) = server.chats.get_mut(&r.chat_id) else {
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            continue;
+                        };
+                        match chat.set_role(r.token, r.username.clone(), r.role) {
+                            Ok(()) => {
+                                info!(chat_id = %r.chat_id, target = r.username, role = ?r.role, "role changed");
+                                send_response(socket, SetRoleResponse(SetRoleResponse {})).await?;
+                            }
+                            Err(err) => {
+                                send_error(socket, err.code, &err.message).await?;
+                            }
+                        }
+                    }
+                    SetTopicRequest(r) => {
+                        let Some(chat) = server.chats.get_mut(&r.chat_id) else {
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            continue;
+                        };
+                        match chat.set_topic(r.token, r.topic) {
+                            Ok(()) => {
+                                info!(chat_id = %r.chat_id, topic = chat.topic.as_deref(), "topic changed");
+                                send_response(socket, SetTopicResponse(SetTopicResponse {})).await?;
+                            }
+                            Err(err) => {
+                                send_error(socket, err.code, &err.message).await?;
+                            }
+                        }
+                    }
+                    SetRoomPasswordRequest(r) => {
+                        let Some(chat) = server.chats.get_mut(&r.chat_id) else {
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            continue;
+                        };
+                        if let Err(err) = chat.owner_only(r.token, "change the password") {
+                            send_error(socket, err.code, &err.message).await?;
+                            continue;
+                        }
+                        let hashed_pw = match r.password.filter(|pw| !pw.is_empty()) {
+                            Some(pw) => Some(hash_password(pw)?),
+                            None => None,
+                        };
+                        let password_protected = hashed_pw.is_some();
+                        match chat.set_password(r.token, hashed_pw, r.kick_old) {
+                            Ok(kicked) => {
+                                info!(chat_id = %r.chat_id, password_protected, kicked = kicked.len(), "room password changed");
+                                stats::members_left(kicked.len());
+                                server.removed_from_chat(r.chat_id, &kicked, "The room password was changed");
+                                send_response(socket, SetRoomPasswordResponse(SetRoomPasswordResponse { kicked })).await?;
+                            }
+                            Err(err) => {
+                                send_error(socket, err.code, &err.message).await?;
+                            }
+                        }
+                    }
+                    CreateInviteRequest(r) => {
+                        let key = server.invite_key.clone();
+                        let Some(chat) = server.chats.get_mut(&r.chat_id) else {
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            continue;
+                        };
+                        match chat.create_invite(r.token, &key, r.chat_id, r.expires_in_secs, r.max_uses) {
+                            Ok(invite) => {
+                                info!(chat_id = %r.chat_id, invite_id = invite.id, expires_at = invite.expires_at, max_uses = invite.max_uses, "invite created");
+                                send_response(socket, CreateInviteResponse(CreateInviteResponse { invite })).await?;
+                            }
+                            Err(err) => {
+                                send_error(socket, err.code, &err.message).await?;
+                            }
+                        }
+                    }
+                    ListInvitesRequest(r) => {
+                        let Some(chat) = server.chats.get_mut(&r.chat_id) else {
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            continue;
+                        };
+                        match chat.list_invites(r.token) {
+                            Ok(invites) => {
+                                send_response(socket, ListInvitesResponse(ListInvitesResponse { invites })).await?;
+                            }
+                            Err(err) => {
+                                send_error(socket, err.code, &err.message).await?;
+                            }
+                        }
+                    }
+                    RevokeInviteRequest(r) => {
+                        let Some(chat) = server.chats.get_mut(&r.chat_id) else {
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            continue;
+                        };
+                        match chat.revoke_invite(r.token, r.invite_id) {
+                            Ok(()) => {
+                                info!(chat_id = %r.chat_id, invite_id = r.invite_id, "invite revoked");
+                                send_response(socket, RevokeInviteResponse(RevokeInviteResponse {})).await?;
+                            }
+                            Err(err) => {
+                                send_error(socket, err.code, &err.message).await?;
+                            }
+                        }
+                    }
+                    PinMessageRequest(r) => {
+                        let Some(chat) = server.chats.get_mut(&r.chat_id) else {
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            continue;
+                        };
+                        match chat.set_pinned(r.token, r.message_id, true) {
+                            Ok(()) => {
+                                info!(chat_id = %r.chat_id, message_id = r.message_id, "message pinned");
+                                send_response(socket, PinMessageResponse(PinResponse {})).await?;
+                            }
+                            Err(err) => {
+                                send_error(socket, err.code, &err.message).await?;
+                            }
+                        }
+                    }
+                    UnpinMessageRequest(r) => {
+                        let Some(chat) = server.chats.get_mut(&r.chat_id) else {
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            continue;
+                        };
+                        match chat.set_pinned(r.token, r.message_id, false) {
+                            Ok(()) => {
+                                info!(chat_id = %r.chat_id, message_id = r.message_id, "message unpinned");
+                                send_response(socket, UnpinMessageResponse(PinResponse {})).await?;
+                            }
+                            Err(err) => {
+                                send_error(socket, err.code, &err.message).await?;
                             }
                         }
                     }
                     LeaveChatRequest(r) => {
                         let Some(chat) = server.chats.get_mut(&r.chat_id) else {
-                            send_error(&mut socket, ErrorCode::ChatNotFound, "Chat not found").await?;
+                            send_error(socket, ErrorCode::ChatNotFound, "Chat not found").await?;
                             continue;
                         };
                         match chat.leave(r.token) {
                             Ok(()) => {
                                 message_receiver = None; // clear receiver when leaving?
-                                current_chat_id = None;
-                                send_response(&mut socket, LeaveChatResponse(LeaveChatResponse {})).await?;
+                                *current_chat = None;
+                                info!(chat_id = %r.chat_id, "left chat");
+                                stats::members_left(1);
+                                server.set_connection_chat(connection_id, None);
+                                send_response(socket, LeaveChatResponse(LeaveChatResponse {})).await?;
                             }
                             Err(err) => {
-                                send_error(&mut socket, err.code, &err.message).await?;
+                                send_error(socket, err.code, &err.message).await?;
                             }
                         }
                     }
+                    Ping(_) => {
+                        send_response(socket, Pong(Pong {})).await?;
+                    }
+                    Pong(_) => {} // last_seen is already bumped, nothing else to do
                     _ => {
                         return Err(Box::new(std::io::Error::new(
                             ErrorKind::InvalidData,
@@ -215,24 +1296,242 @@ async fn handle_connection(mut socket: tokio::net::TcpStream, state: Arc<Mutex<C
                 }
             } => {
                 match msg {
-                    Ok(broadcast_msg) => {
-                        send_response(&mut socket, MessageBroadcast(broadcast_msg)).await?;
+                    Ok(event) => {
+                        send_response(socket, event).await?;
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "connection fell behind on broadcasts, messages dropped");
+                        stats::broadcast_lagged(skipped);
+                    }
+                    Err(RecvError::Closed) => {
+                        // the room is gone, recv would keep returning Closed straight away
+                        debug!("room broadcast closed");
+                        message_receiver = None;
+                        closed_chat = current_chat.take().map(|(chat_id, _)| chat_id);
+                    }
+                }
+            }
+
+            cmd = control.recv() => {
+                match cmd {
+                    Some(Control::Notice(message)) => {
+                        send_response(socket, ServerNotice(ServerNotice { message })).await?;
+                    }
+                    Some(Control::Expiring(warning)) => {
+                        send_response(socket, ChatExpiring(warning)).await?;
+                    }
+                    Some(Control::RemovedFromChat { chat_id, reason }) => {
+                        if closed_chat == Some(chat_id) {
+                            closed_chat = None;
+                        } else if matches!(current_chat, Some((id, _)) if *id == chat_id) {
+                            *current_chat = None;
+                            message_receiver = None;
+                        } else {
+                            continue;
+                        }
+                        info!(%chat_id, reason, "removed from chat");
+                        send_response(socket, RemovedFromChat(RemovedFromChat { chat_id, reason })).await?;
+                    }
+                    Some(Control::Disconnect(reason)) => {
+                        info!(reason, "disconnected by server");
+                        send_response(socket, ServerNotice(ServerNotice { message: reason })).await?;
+                        return Ok(());
                     }
-                    Err(_) => {} // TODO: do I need to do anything here?
+                    None => return Ok(()),
+                }
+            }
+
+            _ = heartbeat.tick() => {
+                let idle = last_seen.elapsed();
+                if idle >= HEARTBEAT_INTERVAL + HEARTBEAT_TIMEOUT {
+                    return Err(Box::new(std::io::Error::new(ErrorKind::TimedOut, "Client stopped answering pings")));
+                }
+                if idle >= HEARTBEAT_INTERVAL {
+                    debug!(idle_secs = idle.as_secs(), "pinging idle client");
+                    send_response(socket, Ping(Ping {})).await?;
                 }
             }
         }
     }
 }
 
-async fn send_error(sock: &mut TcpStream, code: ErrorCode, msg: &str) -> Result<(), DynError> {
+async fn send_error(sock: &mut OwnedWriteHalf, code: ErrorCode, msg: &str) -> Result<(), DynError> {
+    warn!(?code, message = msg, "request failed");
+    stats::error(&code);
     let pkt = Packet { version: 1, message: ErrorResponse(ErrorResponse { code, message: msg.into() }) };
     write_message(sock, &pkt).await?;
     Ok(())
 }
 
-async fn send_response(sock: &mut TcpStream, m: ProtocolMessage) -> Result<(), DynError> {
+async fn send_response(sock: &mut OwnedWriteHalf, m: ProtocolMessage) -> Result<(), DynError> {
     let pkt = Packet { version: 1, message: m };
     write_message(sock, &pkt).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invite(expires_at: Option<u64>, max_uses: Option<u32>, uses: u32) -> Invite {
+        Invite { code: String::new(), created_by: "alice".into(), expires_at, max_uses, uses }
+    }
+
+    #[test]
+    fn invite_expires_at_its_deadline() {
+        assert!(invite(None, None, 0).usable(u64::MAX).is_ok());
+        assert!(invite(Some(100), None, 0).usable(99).is_ok());
+        assert!(matches!(invite(Some(100), None, 0).usable(100), Err(ErrorResponse { code: ErrorCode::InviteExpired, .. })));
+        assert!(invite(Some(100), None, 0).usable(101).is_err());
+    }
+
+    #[test]
+    fn invite_runs_out_of_uses() {
+        assert!(invite(None, None, u32::MAX).usable(0).is_ok());
+        assert!(invite(None, Some(2), 1).usable(0).is_ok());
+        assert!(matches!(invite(None, Some(2), 2).usable(0), Err(ErrorResponse { code: ErrorCode::InviteExpired, .. })));
+        assert!(invite(Some(100), Some(2), 2).usable(0).is_err());
+    }
+
+    #[test]
+    fn invite_expiry_cant_overflow() {
+        let key = InviteKey::generate().unwrap();
+        let chat_id = Uuid::new_v4();
+        let mut chat = ChatRoom::new(None, None, 1);
+        let (token, ..) = chat.join("alice".into(), None, None, None, 1).unwrap();
+
+        let err = chat.create_invite(token, &key, chat_id, Some(u64::MAX), None).unwrap_err();
+        assert!(matches!(err.code, ErrorCode::InvalidFormat));
+        let invite = chat.create_invite(token, &key, chat_id, Some(60), Some(1)).unwrap();
+        assert!(invite.expires_at.is_some_and(|at| at > unix_now()));
+    }
+
+    fn search(query: Option<&str>, author: Option<&str>, before_id: Option<u64>, limit: usize) -> SearchMessagesRequest {
+        SearchMessagesRequest { token: Uuid::nil(), chat_id: Uuid::nil(), query: query.map(Into::into), author: author.map(Into::into), since: None, until: None, before_id, limit: Some(limit) }
+    }
+
+    #[test]
+    fn search_pages_through_matches() {
+        let mut chat = ChatRoom::new(None, None, 1);
+        let (alice, ..) = chat.join("alice".into(), None, None, None, 1).unwrap();
+        let (bob, ..) = chat.join("bob".into(), None, None, None, 2).unwrap();
+        for n in 1..=7 {
+            chat.add_message(if n % 2 == 0 { bob } else { alice }, format!("Note {n}"), None).unwrap();
+        }
+
+        // ids 7, 5, 3, 1 are alice's; pages run newest first and the cursor is the last id handed out
+        let mut req = search(Some("note"), Some("alice"), None, 2);
+        req.token = alice;
+        let (page, next) = chat.search(&req).unwrap();
+        assert_eq!(page.iter().map(|m| m.id).collect::<Vec<_>>(), [7, 5]);
+        assert_eq!(next, Some(5));
+
+        req.before_id = next;
+        let (page, next) = chat.search(&req).unwrap();
+        assert_eq!(page.iter().map(|m| m.id).collect::<Vec<_>>(), [3, 1]);
+        assert_eq!(next, None, "no cursor once nothing is left");
+
+        // a page that ends exactly on the last match doesn't hand out a cursor either
+        req.before_id = None;
+        req.limit = Some(4);
+        assert_eq!(chat.search(&req).unwrap().1, None);
+        req.author = None;
+        assert_eq!(chat.search(&req).unwrap().1, Some(4));
+    }
+
+    #[test]
+    fn search_needs_a_member() {
+        let mut chat = ChatRoom::new(None, None, 1);
+        chat.join("alice".into(), None, None, None, 1).unwrap();
+        assert!(chat.search(&search(None, None, None, 10)).is_err());
+    }
+
+    // alice created the room and owns it, bob and carol are plain members
+    fn room_with_members() -> (ChatRoom, Uuid, Uuid, Uuid) {
+        let mut chat = ChatRoom::new(None, None, 1);
+        let (alice, ..) = chat.join("alice".into(), None, None, None, 1).unwrap();
+        let (bob, ..) = chat.join("bob".into(), None, None, None, 2).unwrap();
+        let (carol, ..) = chat.join("carol".into(), None, None, None, 3).unwrap();
+        (chat, alice, bob, carol)
+    }
+
+    fn forbidden(result: Result<(), ErrorResponse>) -> bool {
+        matches!(result, Err(ErrorResponse { code: ErrorCode::Forbidden, .. }))
+    }
+
+    #[test]
+    fn only_the_author_edits() {
+        let (mut chat, alice, bob, _) = room_with_members();
+        let id = chat.add_message(bob, "hello".into(), None).unwrap();
+        assert!(forbidden(chat.edit_message(alice, id, "hijacked".into())), "not even the owner");
+        chat.edit_message(bob, id, "hello!".into()).unwrap();
+        assert!(chat.messages[0].edited);
+    }
+
+    #[test]
+    fn moderators_delete_other_peoples_messages() {
+        let (mut chat, alice, bob, carol) = room_with_members();
+        let first = chat.add_message(bob, "one".into(), None).unwrap();
+        let second = chat.add_message(bob, "two".into(), None).unwrap();
+        assert!(forbidden(chat.delete_message(carol, first)));
+
+        chat.set_role(alice, "carol".into(), Role::Moderator).unwrap();
+        chat.delete_message(carol, first).unwrap();
+        chat.delete_message(bob, second).unwrap();
+        assert!(chat.messages.is_empty());
+    }
+
+    #[test]
+    fn a_reused_name_isnt_the_same_member() {
+        let (mut chat, _, bob, _) = room_with_members();
+        let id = chat.add_message(bob, "mine".into(), None).unwrap();
+        chat.leave(bob).unwrap();
+
+        let (impostor, ..) = chat.join("bob".into(), None, None, None, 4).unwrap();
+        assert!(forbidden(chat.edit_message(impostor, id, "not mine".into())));
+        assert!(forbidden(chat.delete_message(impostor, id)));
+    }
+
+    #[test]
+    fn privileged_names_need_their_key() {
+        let mut chat = ChatRoom::new(None, None, 1);
+        let (alice, ..) = chat.join("alice".into(), None, None, None, 1).unwrap();
+        let (bob, bob_key, ..) = chat.join("bob".into(), None, None, None, 2).unwrap();
+        chat.set_role(alice, "bob".into(), Role::Moderator).unwrap();
+        chat.leave(bob).unwrap();
+
+        let err = chat.join("bob".into(), None, None, None, 3).unwrap_err();
+        assert!(matches!(err.code, ErrorCode::Forbidden));
+        let (_, key, role, _) = chat.join("bob".into(), None, None, Some(bob_key), 3).unwrap();
+        assert_eq!((key, role), (bob_key, Role::Moderator));
+    }
+
+    #[test]
+    fn roles_only_go_to_names_that_joined() {
+        let (mut chat, alice, bob, _) = room_with_members();
+        assert!(matches!(chat.set_role(alice, "dave".into(), Role::Moderator), Err(ErrorResponse { code: ErrorCode::InvalidFormat, .. })));
+        assert!(!chat.moderators.contains("dave"));
+        assert!(forbidden(chat.set_role(bob, "carol".into(), Role::Moderator)));
+    }
+
+    #[test]
+    fn new_password_kicks_only_password_joiners() {
+        let key = InviteKey::generate().unwrap();
+        let chat_id = Uuid::new_v4();
+        let mut chat = ChatRoom::new(Some(hash_password("old".into()).unwrap()), None, 1);
+        let (alice, ..) = chat.join("alice".into(), Some("old".into()), None, None, 1).unwrap();
+        let (bob, ..) = chat.join("bob".into(), Some("old".into()), None, None, 2).unwrap();
+        let invite = chat.create_invite(alice, &key, chat_id, None, None).unwrap();
+        chat.join("carol".into(), None, Some(invite.id), None, 3).unwrap();
+
+        assert!(forbidden(chat.set_password(bob, None, true).map(|_| ())));
+        assert_eq!(chat.set_password(alice, Some(hash_password("new".into()).unwrap()), false).unwrap(), Vec::<String>::new());
+        assert_eq!(chat.set_password(alice, Some(hash_password("newer".into()).unwrap()), true).unwrap(), ["bob"]);
+
+        let mut members: Vec<&String> = chat.users.iter().collect();
+        members.sort();
+        assert_eq!(members, ["alice", "carol"]);
+        assert!(chat.username(bob).is_err(), "bob's token is gone too");
+        assert!(matches!(chat.join("bob".into(), Some("old".into()), None, None, 2), Err(ErrorResponse { code: ErrorCode::WrongPassword, .. })));
+    }
+}
@@ -0,0 +1,91 @@
+// prometheus metrics, served over http on METRICS_PORT when that's set.
+// without an installed exporter all of these calls are no-ops.
+
+use std::net::SocketAddr;
+
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+use protocol::{DynError, ErrorCode};
+use tokio::time::Instant;
+
+const ACTIVE_CONNECTIONS: &str = "clique_active_connections";
+const ROOMS: &str = "clique_rooms";
+const ROOM_MEMBERS: &str = "clique_room_members";
+const MESSAGES_SENT: &str = "clique_messages_sent_total";
+const ERRORS: &str = "clique_errors_total";
+const BROADCAST_LAG_EVENTS: &str = "clique_broadcast_lag_events_total";
+const BROADCAST_LAGGED_MESSAGES: &str = "clique_broadcast_lagged_messages_total";
+const REQUEST_DURATION: &str = "clique_request_duration_seconds";
+
+// requests mostly take well under a millisecond, except the ones that hash a password
+const REQUEST_DURATION_BUCKETS: &[f64] = &[0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+pub fn install(port: u16) -> Result<(), DynError> {
+    PrometheusBuilder::new()
+        .with_http_listener(SocketAddr::from(([0, 0, 0, 0], port)))
+        .set_buckets_for_metric(Matcher::Full(REQUEST_DURATION.into()), REQUEST_DURATION_BUCKETS)?
+        .install()?;
+
+    describe_gauge!(ACTIVE_CONNECTIONS, "Open client connections");
+    describe_gauge!(ROOMS, "Chat rooms currently held by the server");
+    describe_gauge!(ROOM_MEMBERS, "Members across all chat rooms");
+    describe_counter!(MESSAGES_SENT, "Chat messages accepted from clients");
+    describe_counter!(ERRORS, "Error responses sent to clients, by error code");
+    describe_counter!(BROADCAST_LAG_EVENTS, "Times a connection fell behind its room's broadcast channel");
+    describe_counter!(BROADCAST_LAGGED_MESSAGES, "Broadcast messages dropped because a connection fell behind");
+    describe_histogram!(REQUEST_DURATION, Unit::Seconds, "Time spent handling a request, by request type");
+    Ok(())
+}
+
+pub fn connection_opened() {
+    gauge!(ACTIVE_CONNECTIONS).increment(1.0);
+}
+
+pub fn connection_closed() {
+    gauge!(ACTIVE_CONNECTIONS).decrement(1.0);
+}
+
+pub fn rooms(count: usize) {
+    gauge!(ROOMS).set(count as f64);
+}
+
+// deliberately a total and not a series per room. a room id label is a new series for every
+// room ever created and none of them go away, per-room numbers are in the admin rooms listing
+pub fn member_joined() {
+    gauge!(ROOM_MEMBERS).increment(1.0);
+}
+
+pub fn members_left(count: usize) {
+    gauge!(ROOM_MEMBERS).decrement(count as f64);
+}
+
+pub fn message_sent() {
+    counter!(MESSAGES_SENT).increment(1);
+}
+
+pub fn error(code: &ErrorCode) {
+    counter!(ERRORS, "code" => code.as_str()).increment(1);
+}
+
+pub fn broadcast_lagged(skipped: u64) {
+    counter!(BROADCAST_LAG_EVENTS).increment(1);
+    counter!(BROADCAST_LAGGED_MESSAGES).increment(skipped);
+}
+
+// records how long a request took when dropped, so early returns and continues still get counted
+pub struct RequestTimer {
+    kind: &'static str,
+    started: Instant,
+}
+
+impl RequestTimer {
+    pub fn start(kind: &'static str) -> Self {
+        RequestTimer { kind, started: Instant::now() }
+    }
+}
+
+impl Drop for RequestTimer {
+    fn drop(&mut self) {
+        histogram!(REQUEST_DURATION, "type" => self.kind).record(self.started.elapsed().as_secs_f64());
+    }
+}
@@ -0,0 +1,195 @@
+// runs the server binary on a free port and drives it through cliqueclient
+
+use std::{future::Future, net::TcpListener, path::PathBuf, process::Stdio, time::Duration};
+
+use cliqueclient::{Client, ClientError, Event, Events};
+use protocol::{read_message, write_message, AdminCommand, AdminResponse, ErrorCode, ErrorResponse, Packet, ProtocolMessage, Role, RoomRef};
+use tokio::{
+    net::{TcpStream, UnixStream},
+    process::{Child, Command},
+    time,
+};
+use uuid::Uuid;
+
+const WAIT: Duration = Duration::from_secs(10);
+
+struct TestServer {
+    addr: String,
+    admin_socket: PathBuf,
+    _child: Child, // killed on drop
+}
+
+async fn start_server() -> TestServer {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let admin_socket = std::env::temp_dir().join(format!("clique-test-{}-{}.sock", std::process::id(), port));
+    let child = Command::new(env!("CARGO_BIN_EXE_server"))
+        .env("PORT", port.to_string())
+        .env("ADMIN_SOCKET", &admin_socket)
+        .env("LOG_LEVEL", "warn")
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .expect("server should start");
+
+    let addr = format!("127.0.0.1:{}", port);
+    within(async {
+        while TcpStream::connect(&addr).await.is_err() {
+            time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    TestServer { addr, admin_socket, _child: child }
+}
+
+async fn within<T>(fut: impl Future<Output = T>) -> T {
+    time::timeout(WAIT, fut).await.expect("timed out")
+}
+
+// skips events until one matches
+async fn wait_for<T>(events: &mut Events, mut matches: impl FnMut(Event) -> Option<T>) -> T {
+    within(async {
+        loop {
+            let event = events.next().await.expect("events ended");
+            if let Some(found) = matches(event) {
+                return found;
+            }
+        }
+    })
+    .await
+}
+
+async fn admin(server: &TestServer, cmd: AdminCommand) -> AdminResponse {
+    let mut socket = UnixStream::connect(&server.admin_socket).await.unwrap();
+    write_message(&mut socket, &Packet { version: 1, message: ProtocolMessage::AdminRequest(cmd) }).await.unwrap();
+    match read_message(&mut socket).await.unwrap().message {
+        ProtocolMessage::AdminResponse(resp) => resp,
+        other => panic!("unexpected admin response: {other:?}"),
+    }
+}
+
+async fn connect(server: &TestServer) -> (Client, Events) {
+    within(cliqueclient::connect(&server.addr)).await.expect("connect")
+}
+
+#[tokio::test]
+async fn create_join_send_leave() {
+    let server = start_server().await;
+    let (alice, mut alice_events) = connect(&server).await;
+    let (bob, mut bob_events) = connect(&server).await;
+
+    let chat_id = alice.create_chat(None, None).await.unwrap().chat_id;
+    alice.join(chat_id, "alice".into(), None).await.unwrap();
+    bob.join(chat_id, "bob".into(), None).await.unwrap();
+    wait_for(&mut alice_events, |event| matches!(event, Event::MemberJoined(member) if member.username == "bob").then_some(())).await;
+
+    let sent = bob.send("hello".into()).await.unwrap();
+    let msg = wait_for(&mut alice_events, |event| match event {
+        Event::Message(msg) if msg.username == "bob" => Some(msg),
+        _ => None,
+    })
+    .await;
+    assert_eq!(msg.id, sent.message_id);
+    assert_eq!(msg.message, "hello");
+
+    bob.leave().await.unwrap();
+    assert!(bob.session().await.is_none());
+    wait_for(&mut alice_events, |event| matches!(event, Event::MemberLeft(member) if member.username == "bob").then_some(())).await;
+
+    // nothing from the room reaches bob after leaving
+    alice.send("still here".into()).await.unwrap();
+    time::sleep(Duration::from_millis(200)).await;
+    while let Ok(Some(event)) = time::timeout(Duration::from_millis(50), bob_events.next()).await {
+        assert!(!matches!(event, Event::Message(ref msg) if msg.message == "still here"), "got {event:?} after leaving");
+    }
+}
+
+#[tokio::test]
+async fn reconnects_and_rejoins() {
+    let server = start_server().await;
+    let (alice, mut alice_events) = connect(&server).await;
+    let (bob, mut bob_events) = connect(&server).await;
+
+    let chat_id = alice.create_chat(None, None).await.unwrap().chat_id;
+    alice.join(chat_id, "alice".into(), None).await.unwrap();
+    bob.join(chat_id, "bob".into(), None).await.unwrap();
+
+    match admin(&server, AdminCommand::DisconnectUser { username: "bob".into() }).await {
+        AdminResponse::Done { .. } => {}
+        other => panic!("disconnect-user failed: {other:?}"),
+    }
+    wait_for(&mut bob_events, |event| matches!(event, Event::Disconnected(_)).then_some(())).await;
+    let rejoined = wait_for(&mut bob_events, |event| match event {
+        Event::Reconnected { rejoined } => Some(rejoined),
+        _ => None,
+    })
+    .await;
+    let rejoined = rejoined.expect("bob was in a chat").expect("rejoin should succeed");
+    assert_eq!(bob.session().await.map(|session| session.token), Some(rejoined.token));
+    assert!(bob.is_connected());
+
+    bob.send("back again".into()).await.unwrap();
+    wait_for(&mut alice_events, |event| matches!(event, Event::Message(msg) if msg.message == "back again").then_some(())).await;
+}
+
+#[tokio::test]
+async fn lists_only_rooms_we_were_in() {
+    let server = start_server().await;
+    let (alice, _alice_events) = connect(&server).await;
+    let (mallory, _mallory_events) = connect(&server).await;
+
+    let chat_id = alice.create_chat(None, None).await.unwrap().chat_id;
+    let joined = alice.join(chat_id, "alice".into(), None).await.unwrap();
+    alice.send("hi".into()).await.unwrap();
+    alice.leave().await.unwrap();
+
+    let ours = RoomRef { chat_id, username: "alice".into(), member_key: joined.member_key };
+    let listed = alice.list_rooms(vec![ours.clone()]).await.unwrap().rooms;
+    assert_eq!(listed.len(), 1);
+    assert!(listed[0].last_message_at.is_some());
+
+    // the id and name alone aren't enough
+    let guessed = RoomRef { member_key: Uuid::new_v4(), ..ours };
+    assert!(mallory.list_rooms(vec![guessed]).await.unwrap().rooms.is_empty());
+}
+
+#[tokio::test]
+async fn carries_on_after_the_room_is_deleted() {
+    let server = start_server().await;
+    let (alice, mut alice_events) = connect(&server).await;
+
+    let chat_id = alice.create_chat(None, None).await.unwrap().chat_id;
+    alice.join(chat_id, "alice".into(), None).await.unwrap();
+    match admin(&server, AdminCommand::DeleteRoom { chat_id }).await {
+        AdminResponse::Done { .. } => {}
+        other => panic!("delete-room failed: {other:?}"),
+    }
+    wait_for(&mut alice_events, |event| matches!(event, Event::RemovedFromChat(removed) if removed.chat_id == chat_id).then_some(())).await;
+
+    // the connection still answers and can move on to another room
+    let next = alice.create_chat(None, None).await.unwrap().chat_id;
+    alice.join(next, "alice".into(), None).await.unwrap();
+    alice.send("moved".into()).await.unwrap();
+    wait_for(&mut alice_events, |event| matches!(event, Event::Message(msg) if msg.message == "moved").then_some(())).await;
+}
+
+#[tokio::test]
+async fn owner_rejoins_from_a_fresh_client() {
+    let server = start_server().await;
+    let (first, _first_events) = connect(&server).await;
+    let chat_id = first.create_chat(None, None).await.unwrap().chat_id;
+    let joined = first.join(chat_id, "alice".into(), None).await.unwrap();
+    assert_eq!(joined.role, Role::Owner);
+    // as good as the process going away, the name is free but still bound to the key
+    first.leave().await.unwrap();
+
+    // a new process without the key can't take the owner's name
+    let (stranger, _stranger_events) = connect(&server).await;
+    let refused = stranger.join(chat_id, "alice".into(), None).await;
+    assert!(matches!(refused, Err(ClientError::Server(ErrorResponse { code: ErrorCode::Forbidden, .. }))), "got {refused:?}");
+
+    let (restarted, _restarted_events) = connect(&server).await;
+    restarted.remember_member_key(chat_id, "alice".into(), joined.member_key).await;
+    let rejoined = restarted.join(chat_id, "alice".into(), None).await.unwrap();
+    assert_eq!((rejoined.role, rejoined.member_key), (Role::Owner, joined.member_key));
+    restarted.set_topic(Some("still mine".into())).await.unwrap();
+}